@@ -0,0 +1,126 @@
+use super::{Symbol, Pratt, ParseError};
+use super::lexer::{Lexeme, StringTokenizer};
+use super::events::{self, Sink};
+
+//  concrete syntax tree: every token of the input in source order, trivia
+//  included, grouped into one node per nud/led call; printing it gives back
+//  the exact input, whatever the nud/led build as their own tree
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CstElement {
+    Node(CstNode),
+    Token(Lexeme),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CstNode {
+    // kind of the token whose nud/led made the node, "root" at the top
+    pub kind: String,
+    pub children: Vec<CstElement>,
+}
+
+//  typed view of a node, guessed from the shape of its children;
+//  groups, calls and mixfix constructs stay Other
+#[derive(Debug, PartialEq)]
+pub enum Ast<'a> {
+    Atom(&'a Lexeme),
+    Prefix { op: &'a Lexeme, operand: &'a CstNode },
+    Infix { left: &'a CstNode, op: &'a Lexeme, right: &'a CstNode },
+    Postfix { operand: &'a CstNode, op: &'a Lexeme },
+    Other(&'a CstNode),
+}
+
+impl CstNode {
+    pub fn text(&self) -> String {
+        let mut out = String::new();
+        self.write(&mut out);
+        out
+    }
+
+    fn write(&self, out: &mut String) {
+        for child in &self.children {
+            match *child {
+                CstElement::Node(ref n) => n.write(out),
+                CstElement::Token(ref t) => out.push_str(&t.full_text()),
+            }
+        }
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &CstNode> {
+        self.children.iter().filter_map(|c| match *c {
+            CstElement::Node(ref n) => Some(n),
+            CstElement::Token(_) => None,
+        })
+    }
+
+    pub fn tokens(&self) -> impl Iterator<Item = &Lexeme> {
+        self.children.iter().filter_map(|c| match *c {
+            CstElement::Token(ref t) => Some(t),
+            CstElement::Node(_) => None,
+        })
+    }
+
+    pub fn ast(&self) -> Ast<'_> {
+        use self::CstElement::{Node, Token};
+        match self.children[..] {
+            [Token(ref t)] => Ast::Atom(t),
+            [Token(ref op), Node(ref operand)] => Ast::Prefix { op, operand },
+            [Node(ref left), Token(ref op), Node(ref right)] => Ast::Infix { left, op, right },
+            [Node(ref operand), Token(ref op)] => Ast::Postfix { operand, op },
+            _ => Ast::Other(self),
+        }
+    }
+}
+
+
+//  a Sink that builds the CstNode of a parse;
+//  the tokenizer has to be in lossless mode to keep the trivia
+#[derive(Default)]
+pub struct CstBuilder {
+    // the children of the open nodes, one after the other
+    children: Vec<CstElement>,
+    // kind and first child of each open node
+    parents: Vec<(String, usize)>,
+    root: Option<CstNode>,
+}
+
+impl CstBuilder {
+    pub fn new() -> CstBuilder {
+        CstBuilder::default()
+    }
+
+    pub fn finish(self) -> Option<CstNode> {
+        self.root
+    }
+}
+
+impl Sink for CstBuilder {
+    fn start_node(&mut self, kind: &str) {
+        self.parents.push((String::from(kind), self.children.len()));
+    }
+    fn token(&mut self, lexeme: Lexeme) {
+        self.children.push(CstElement::Token(lexeme));
+    }
+    fn finish_node(&mut self) {
+        let (kind, first) = self.parents.pop().unwrap();
+        let node = CstNode { kind, children: self.children.split_off(first) };
+        if self.parents.is_empty() {
+            self.root = Some(node);
+        } else {
+            self.children.push(CstElement::Node(node));
+        }
+    }
+    fn checkpoint(&self) -> usize {
+        self.children.len()
+    }
+    fn start_node_at(&mut self, checkpoint: usize, kind: &str) {
+        self.parents.push((String::from(kind), checkpoint));
+    }
+}
+
+//  parses the tokenizer's input into a CST, turning on lossless mode
+pub fn parse<S: Symbol + 'static>(tokenizer: StringTokenizer<S>) -> Result<CstNode, ParseError> {
+    tokenizer.set_lossless(true);
+    let builder = events::parse(Pratt::new(tokenizer), CstBuilder::new())?;
+    Ok(builder.finish().unwrap())
+}
@@ -1,17 +1,38 @@
 use std::fmt;
 use super::{PrattBox, Token, Symbol, Pratt};
+use super::lexer::Lexeme;
 #[cfg(feature="gc3c")]
 use gc3c::{Mark,InGcEnv};
 //#[cfg(not(feature="gc3c"))]
 //use super::Mark;
 use std::rc::Rc;
  
+pub type Fnud = Rc<dyn Fn(&mut DynamicToken, PrattBox<DynamicSymbol>, &Pratt<DynamicSymbol>)->PrattBox<DynamicSymbol>>;
+pub type Fled = Rc<dyn Fn(&mut DynamicToken, PrattBox<DynamicSymbol>, &Pratt<DynamicSymbol>, PrattBox<DynamicSymbol>)->PrattBox<DynamicSymbol>>;
+
 pub struct DynamicToken  {
     pub code: String,
     pub children: Vec<PrattBox<DynamicSymbol>>,
     pub lbp: u8,
-    pub fnud: Rc<dyn Fn(&mut DynamicToken, PrattBox<DynamicSymbol>, &Pratt<DynamicSymbol>)->PrattBox<DynamicSymbol>>,
-    pub fled: Rc<dyn Fn(&mut DynamicToken, PrattBox<DynamicSymbol>, &Pratt<DynamicSymbol>, PrattBox<DynamicSymbol>)->PrattBox<DynamicSymbol>>,
+    pub fnud: Fnud,
+    pub fled: Fled,
+    pub extra: TokenExtra,
+}
+
+//  what a token knows beyond code, binding power and nud/led; literals
+//  take 'extra: TokenExtra::default()' or '..TokenExtra::default()', so
+//  that fields added here later do not break them
+#[derive(Debug, Clone, Default)]
+pub struct TokenExtra {
+    pub lexeme: Lexeme,
+    // the led needs no line break before the token
+    pub restricted: bool,
+    // spaced before and not after, the token starts an operand: no led
+    pub spaced_prefix: bool,
+    // fnud does more than fail, the token can start an operand
+    pub has_nud: bool,
+    // the token ends a top-level expression, see Grammar::end()
+    pub end: bool,
 }
 
 pub struct DynamicSymbol {
@@ -22,6 +43,13 @@ impl Symbol for DynamicSymbol {
     fn token(&mut self) -> &mut dyn Token<DynamicSymbol> {
         &mut self.token
     }
+    fn lexeme(&mut self) -> Option<&mut Lexeme> {
+        Some(&mut self.token.extra.lexeme)
+    }
+    // tokens not made by a grammar end expressions as the "end" kind
+    fn is_end(&mut self) -> bool {
+        self.token.extra.end || self.token.extra.lexeme.kind == "end"
+    }
 }
 
 impl fmt::Debug for DynamicSymbol {
@@ -40,7 +68,30 @@ impl fmt::Debug for DynamicToken {
     }
 }
 
+//  s-expression: leaves print their code, inner nodes "(code child ...)"
+impl fmt::Display for DynamicSymbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.token.fmt(f)
+    }
+}
+
+impl fmt::Display for DynamicToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.children.is_empty() {
+            return write!(f, "{}", self.code);
+        }
+        write!(f, "({}", self.code)?;
+        for c in &self.children {
+            write!(f, " {}", *c.borrow_mut())?;
+        }
+        write!(f, ")")
+    }
+}
+
 impl DynamicToken {
+    pub fn new(code: &str, lbp: u8, fnud: Fnud, fled: Fled) -> DynamicToken {
+        DynamicToken { code: String::from(code), children: vec![], lbp, fnud, fled, extra: TokenExtra::default() }
+    }
     pub fn add_child(&mut self, child: PrattBox<DynamicSymbol>) {
         self.children.push(child);
     }
@@ -63,6 +114,15 @@ impl Token<DynamicSymbol> for DynamicToken  {
     fn lbp(&self) -> u8 {
         self.lbp
     }
+    fn spaced_lbp(&self, space_before: bool, space_after: bool) -> u8 {
+        if self.extra.spaced_prefix && space_before && !space_after { 0 } else { self.lbp }
+    }
+    fn restricted(&self) -> bool {
+        self.extra.restricted
+    }
+    fn has_nud(&self) -> bool {
+        self.extra.has_nud
+    }
 }
 //#[cfg(not(feature="gc3c"))]
 //impl Mark for DynamicSymbol {}
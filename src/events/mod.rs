@@ -0,0 +1,312 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+#[cfg(feature="gc3c")]
+use gc3c::gc;
+use super::{PrattBox, Symbol, Tokenizer, Pratt, ParseError};
+use super::dyn::{DynamicSymbol, DynamicToken, Fnud, Fled};
+use super::grammar::{Grammar, Prefix, Rule};
+use super::lexer::Lexeme;
+use super::trace::{TraceEvent, Tracer};
+
+//  a parse as a flat stream of start node / token / finish node events:
+//  one node per nud/led call, every token of the input in source order
+//  (the lexemes carry trivia when the tokenizer is lossless), all wrapped
+//  in a "root" node that also holds the end token. The events come from a
+//  tracer, sent to the sink as nud and led run; parse() and parse_all()
+//  turn off the parser's own tree, so the sink's is the only one built
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    StartNode(String),
+    Token(Lexeme),
+    FinishNode,
+}
+
+pub trait Sink {
+    fn start_node(&mut self, kind: &str);
+    fn token(&mut self, lexeme: Lexeme);
+    fn finish_node(&mut self);
+    //  where the next node or token goes in the node open now
+    fn checkpoint(&self) -> usize;
+    //  starts a node holding what came since 'checkpoint', taken in the
+    //  node open now: a led wraps its left operand that way
+    fn start_node_at(&mut self, checkpoint: usize, kind: &str);
+}
+
+//  records the events
+impl Sink for Vec<Event> {
+    fn start_node(&mut self, kind: &str) {
+        self.push(Event::StartNode(String::from(kind)));
+    }
+    fn token(&mut self, lexeme: Lexeme) {
+        self.push(Event::Token(lexeme));
+    }
+    fn finish_node(&mut self) {
+        self.push(Event::FinishNode);
+    }
+    fn checkpoint(&self) -> usize {
+        self.len()
+    }
+    fn start_node_at(&mut self, checkpoint: usize, kind: &str) {
+        self.insert(checkpoint, Event::StartNode(String::from(kind)));
+    }
+}
+
+//  drops the events, the parse behind them still checks the input
+impl Sink for () {
+    fn start_node(&mut self, _kind: &str) {}
+    fn token(&mut self, _lexeme: Lexeme) {}
+    fn finish_node(&mut self) {}
+    fn checkpoint(&self) -> usize {
+        0
+    }
+    fn start_node_at(&mut self, _checkpoint: usize, _kind: &str) {}
+}
+
+
+struct Stream {
+    // checkpoints of the open nodes
+    open: Vec<usize>,
+    // checkpoint of the node returned last, the left operand of a led
+    closed: Option<usize>,
+    // tokens advanced past but not placed yet
+    pending: Vec<Lexeme>,
+    current: Option<Lexeme>,
+}
+
+impl Stream {
+    fn flush<K: Sink>(&mut self, sink: &mut K) {
+        for lexeme in self.pending.drain(..) {
+            sink.token(lexeme);
+        }
+    }
+
+    //  places the pending tokens before the node of 'op' and starts it,
+    //  around the node at 'left' for a led; the operator token was the
+    //  last one advanced past
+    fn start<K: Sink>(&mut self, sink: &mut K, op: Option<Lexeme>, left: Option<usize>) {
+        let kind = op.as_ref().map_or(String::new(), |op| op.kind.clone());
+        let last = match (self.pending.last(), op) {
+            (Some(last), Some(op)) => last.span == op.span,
+            _ => false,
+        };
+        let token = if last { self.pending.pop() } else { None };
+        self.flush(sink);
+        let checkpoint = match left {
+            Some(left) => {
+                sink.start_node_at(left, &kind);
+                left
+            }
+            None => {
+                let checkpoint = sink.checkpoint();
+                sink.start_node(&kind);
+                checkpoint
+            }
+        };
+        if let Some(token) = token {
+            sink.token(token);
+        }
+        self.open.push(checkpoint);
+    }
+
+    fn finish<K: Sink>(&mut self, sink: &mut K) {
+        self.flush(sink);
+        sink.finish_node();
+        self.closed = self.open.pop();
+    }
+}
+
+//  a Tracer that turns the trace of a parse into events for 'sink', sent
+//  as they come: only the tokens advanced past wait, until the node they
+//  go in is known
+pub struct EventTracer<K: Sink> {
+    stream: RefCell<Stream>,
+    sink: RefCell<Option<K>>,
+}
+
+impl<K: Sink> EventTracer<K> {
+    pub fn new(mut sink: K) -> EventTracer<K> {
+        sink.start_node("root");
+        let stream = Stream { open: vec![0], closed: None, pending: vec![], current: None };
+        EventTracer { stream: RefCell::new(stream), sink: RefCell::new(Some(sink)) }
+    }
+
+    //  sends the tokens not consumed yet (the end token) to the root and
+    //  closes it, then gives back the sink
+    pub fn finish(&self) -> K {
+        let mut s = self.stream.borrow_mut();
+        let mut sink = self.sink.borrow_mut().take().expect("finished twice");
+        if let Some(current) = s.current.take() {
+            s.pending.push(current);
+        }
+        while !s.open.is_empty() {
+            s.finish(&mut sink);
+        }
+        sink
+    }
+}
+
+fn lexeme<S: Symbol>(t: &PrattBox<S>) -> Option<Lexeme> {
+    t.borrow_mut().lexeme().cloned()
+}
+
+impl<S: Symbol, K: Sink> Tracer<S> for EventTracer<K> {
+    fn trace(&self, event: &TraceEvent<S>) {
+        let mut s = self.stream.borrow_mut();
+        let mut sink = self.sink.borrow_mut();
+        let sink = sink.as_mut().expect("traced after finish");
+        match *event {
+            TraceEvent::Advance(t) | TraceEvent::Inserted(t) => {
+                let next = lexeme(t);
+                // at the end of input the tokenizer stays on its last token
+                if next.is_some() && s.current.as_ref().map(|c| &c.span) != next.as_ref().map(|n| &n.span) {
+                    if let Some(current) = s.current.take() {
+                        s.pending.push(current);
+                    }
+                    s.current = next;
+                }
+            }
+            TraceEvent::Relex(t) => {
+                if let Some(next) = lexeme(t) {
+                    s.current = Some(next);
+                }
+            }
+            TraceEvent::Nud(op) => s.start(sink, lexeme(op), None),
+            TraceEvent::Led(op, _) => {
+                let left = s.closed.take();
+                s.start(sink, lexeme(op), left);
+            }
+            TraceEvent::Return(_) => s.finish(sink),
+            TraceEvent::Compare { .. } => {}
+        }
+    }
+}
+
+//  parses with 'pratt' and sends the events to 'sink'; nud/led build no
+//  tree of their own
+pub fn parse<S: Symbol + 'static, T: Tokenizer<S> + 'static, K: Sink + 'static>(mut pratt: Pratt<S, T>, sink: K) -> Result<K, ParseError> {
+    let tracer = Rc::new(EventTracer::new(sink));
+    pratt.set_tracer(tracer.clone());
+    pratt.set_build_tree(false);
+    pratt.try_pparse()?;
+    Ok(tracer.finish())
+}
+
+//  like parse(), for every expression up to the end of input: the root
+//  holds them all, each followed by its end token; the first syntax
+//  error is the result
+pub fn parse_all<S: Symbol + 'static, T: Tokenizer<S> + 'static, K: Sink + 'static>(mut pratt: Pratt<S, T>, sink: K) -> Result<K, ParseError> {
+    let tracer = Rc::new(EventTracer::new(sink));
+    pratt.set_tracer(tracer.clone());
+    pratt.set_build_tree(false);
+    for result in pratt.expressions() {
+        result?;
+    }
+    Ok(tracer.finish())
+}
+
+
+struct Frame {
+    tokens: Vec<Lexeme>,
+    nodes: Vec<PrattBox<DynamicSymbol>>,
+    kind: String,
+    // a node came before the operator: made by a led
+    infix: bool,
+}
+
+//  a Sink that builds from the events a copy of the DynamicSymbol tree the
+//  grammar's nud/led built, e.g. to check the events against it
+pub struct DynamicBuilder {
+    grammar: Grammar,
+    // the tokens and nodes of the open nodes, one after the other
+    elements: Vec<Element>,
+    // kind and first element of each open node
+    parents: Vec<(String, usize)>,
+    root: Option<PrattBox<DynamicSymbol>>,
+}
+
+enum Element {
+    Token(Lexeme),
+    Node(PrattBox<DynamicSymbol>),
+}
+
+impl DynamicBuilder {
+    pub fn new(grammar: &Grammar) -> DynamicBuilder {
+        DynamicBuilder { grammar: grammar.clone(), elements: vec![], parents: vec![], root: None }
+    }
+
+    pub fn finish(self) -> Option<PrattBox<DynamicSymbol>> {
+        self.root
+    }
+
+    fn build(&self, frame: Frame) -> Option<PrattBox<DynamicSymbol>> {
+        // the juxtaposition has no token of its own
+        let juxtaposition = self.grammar.juxtaposition_rule().filter(|r| r.name == frame.kind);
+        let (code, rule) = match (frame.tokens.first(), juxtaposition) {
+            (Some(op), _) => (op.code(), self.grammar.rule(&frame.kind)),
+            (None, Some(rule)) => (rule.name, Some(rule)),
+            (None, None) => return frame.nodes.into_iter().next(),
+        };
+        // a group is the inner expression
+        if let Some(&Rule { prefix: Some(Prefix::Group { .. }), .. }) = rule {
+            if !frame.infix {
+                return frame.nodes.into_iter().next();
+            }
+        }
+        let node = match rule {
+            Some(rule) => rule.token(code),
+            None => {
+                let fnud: Fnud = Rc::new(|_se, this, _pratt| this);
+                let fled: Fled = Rc::new(|_se, _this, _pratt, left| left);
+                prattbox!(DynamicSymbol { token: DynamicToken::new(code, 0, fnud, fled) })
+            }
+        };
+        {
+            let mut symbol = node.borrow_mut();
+            for child in frame.nodes {
+                symbol.token.add_child(child);
+            }
+            if let Some(op) = frame.tokens.into_iter().next() {
+                symbol.token.extra.lexeme = op;
+            }
+        }
+        Some(node)
+    }
+}
+
+impl Sink for DynamicBuilder {
+    fn start_node(&mut self, kind: &str) {
+        self.parents.push((String::from(kind), self.elements.len()));
+    }
+    fn token(&mut self, lexeme: Lexeme) {
+        self.elements.push(Element::Token(lexeme));
+    }
+    fn finish_node(&mut self) {
+        let (kind, first) = self.parents.pop().unwrap();
+        let mut frame = Frame { tokens: vec![], nodes: vec![], kind, infix: false };
+        for element in self.elements.split_off(first) {
+            match element {
+                Element::Token(lexeme) => {
+                    if frame.tokens.is_empty() && !frame.nodes.is_empty() {
+                        frame.infix = true;
+                    }
+                    frame.tokens.push(lexeme);
+                }
+                Element::Node(node) => frame.nodes.push(node),
+            }
+        }
+        let root = frame.kind == "root" && self.parents.is_empty();
+        let node = if root { frame.nodes.into_iter().next() } else { self.build(frame) };
+        if self.parents.is_empty() {
+            self.root = node;
+        } else {
+            self.elements.extend(node.map(Element::Node));
+        }
+    }
+    fn checkpoint(&self) -> usize {
+        self.elements.len()
+    }
+    fn start_node_at(&mut self, checkpoint: usize, kind: &str) {
+        self.parents.push((String::from(kind), checkpoint));
+    }
+}
@@ -0,0 +1,478 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+#[cfg(not(feature="gc3c"))]
+use std::cell::RefCell;
+#[cfg(feature="gc3c")]
+use gc3c::gc;
+use super::{PrattBox, Pratt, ParseError, Symbol};
+use super::dyn::{DynamicSymbol, DynamicToken, Fnud, Fled};
+use super::lexer::StringTokenizer;
+
+//  a grammar for DynamicSymbol trees, described instead of hand written:
+//  every token name the lexer can produce gets a rule that knows its binding
+//  powers and what its nud and led do, so the grammar can be checked and
+//  installed into a tokenizer
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assoc {
+    Left,
+    Right,
+}
+
+//  what a token does in operand position
+#[derive(Clone)]
+pub enum Prefix {
+    // the token is the operand itself
+    Atom,
+    Operator { rbp: u8 },
+    // open ... close, the nud returns the inner expression
+    Group { close: &'static str },
+    // if c then a else b: an expression before each part, the last one with rbp
+    Mixfix { parts: Vec<&'static str>, rbp: u8 },
+    Custom(Fnud),
+}
+
+//  what a token does after an operand
+#[derive(Clone)]
+pub enum Infix {
+    Operator { rbp: u8 },
+    Postfix,
+    // f(a, b): left operand followed by a separated argument list
+    Call { sep: &'static str, close: &'static str },
+    // c ? a : b
+    Mixfix { parts: Vec<&'static str>, rbp: u8 },
+    Custom(Fled),
+}
+
+#[derive(Clone)]
+pub struct Rule {
+    pub name: &'static str,
+    pub lbp: u8,
+    pub end: bool,
+    pub prefix: Option<Prefix>,
+    pub infix: Option<Infix>,
+    // no line break allowed before the led
+    pub restricted: bool,
+    // no led when spaced before and not after
+    pub spaced_prefix: bool,
+}
+
+impl Rule {
+    fn new(name: &'static str) -> Rule {
+        Rule { name, lbp: 0, end: false, prefix: None, infix: None, restricted: false, spaced_prefix: false }
+    }
+
+    pub fn assoc(&self) -> Option<Assoc> {
+        match self.infix {
+            Some(Infix::Operator { rbp }) | Some(Infix::Mixfix { rbp, .. }) if rbp == self.lbp => Some(Assoc::Left),
+            Some(Infix::Operator { rbp }) | Some(Infix::Mixfix { rbp, .. }) if rbp.checked_add(1) == Some(self.lbp) => Some(Assoc::Right),
+            _ => None,
+        }
+    }
+
+    fn fnud(&self) -> Fnud {
+        match self.prefix {
+            Some(Prefix::Atom) => Rc::new(|_se, this, _pratt| { this }),
+            Some(Prefix::Operator { rbp }) => Rc::new(move |se, this, pratt| {
+                add_child(se, pratt, pratt.parse(rbp));
+                this
+            }),
+            Some(Prefix::Group { close }) => Rc::new(move |_se, _this, pratt| {
+                let inner = pratt.parse(0);
+                expect(pratt, close);
+                inner
+            }),
+            Some(Prefix::Mixfix { ref parts, rbp }) => {
+                let parts = parts.clone();
+                Rc::new(move |se, this, pratt| {
+                    for part in &parts {
+                        add_child(se, pratt, pratt.parse(0));
+                        expect(pratt, part);
+                    }
+                    add_child(se, pratt, pratt.parse(rbp));
+                    this
+                })
+            }
+            Some(Prefix::Custom(ref f)) => f.clone(),
+            None => Rc::new(|se, this, pratt| {
+                pratt.fail(ParseError::Syntax(format!("unexpected {}", se.code)));
+                this
+            }),
+        }
+    }
+
+    fn fled(&self) -> Fled {
+        match self.infix {
+            Some(Infix::Operator { rbp }) => Rc::new(move |se, this, pratt, left| {
+                add_child(se, pratt, left);
+                add_child(se, pratt, pratt.parse(rbp));
+                this
+            }),
+            Some(Infix::Postfix) => Rc::new(|se, this, pratt, left| {
+                add_child(se, pratt, left);
+                this
+            }),
+            Some(Infix::Call { sep, close }) => Rc::new(move |se, this, pratt, left| {
+                add_child(se, pratt, left);
+                if !at(pratt, close) {
+                    add_child(se, pratt, pratt.parse(0));
+                    while at(pratt, sep) {
+                        pratt.advance();
+                        add_child(se, pratt, pratt.parse(0));
+                    }
+                }
+                expect(pratt, close);
+                this
+            }),
+            Some(Infix::Mixfix { ref parts, rbp }) => {
+                let parts = parts.clone();
+                Rc::new(move |se, this, pratt, left| {
+                    add_child(se, pratt, left);
+                    for part in &parts {
+                        add_child(se, pratt, pratt.parse(0));
+                        expect(pratt, part);
+                    }
+                    add_child(se, pratt, pratt.parse(rbp));
+                    this
+                })
+            }
+            Some(Infix::Custom(ref f)) => f.clone(),
+            None => Rc::new(|se, _this, pratt, left| {
+                pratt.fail(ParseError::Syntax(format!("{} is not an infix operator", se.code)));
+                left
+            }),
+        }
+    }
+
+    pub fn token(&self, code: &str) -> PrattBox<DynamicSymbol> {
+        let mut token = DynamicToken::new(code, self.lbp, self.fnud(), self.fled());
+        token.extra.restricted = self.restricted;
+        token.extra.spaced_prefix = self.spaced_prefix;
+        token.extra.has_nud = self.prefix.is_some();
+        token.extra.end = self.end;
+        prattbox!(DynamicSymbol { token })
+    }
+}
+
+//  left out when the parser builds no tree
+fn add_child(se: &mut DynamicToken, pratt: &Pratt<DynamicSymbol>, child: PrattBox<DynamicSymbol>) {
+    if pratt.build_tree() {
+        se.add_child(child);
+    }
+}
+
+fn current_code(pratt: &Pratt<DynamicSymbol>) -> Option<String> {
+    pratt.current().map(|t| t.borrow_mut().token.code.clone())
+}
+
+pub fn at(pratt: &Pratt<DynamicSymbol>, code: &str) -> bool {
+    pratt.error().is_none() && current_code(pratt).as_deref() == Some(code)
+}
+
+//  skip the expected closing token or fail the parse
+pub fn expect(pratt: &Pratt<DynamicSymbol>, code: &str) {
+    let found = current_code(pratt);
+    if found.as_deref() == Some(code) {
+        pratt.advance();
+    } else {
+        pratt.fail(ParseError::Syntax(format!("expected {} found {}", code, found.unwrap_or_default())));
+    }
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Issue {
+    // lbp > 0 puts the token in infix position, but it has no led
+    MissingLed { name: &'static str, lbp: u8 },
+    // a nud-only token with lbp > 0 ends the left operand and then fails
+    PrefixWithLbp { name: &'static str, lbp: u8 },
+    MissingEnd,
+    // a later registration replaced an earlier one
+    Duplicate { name: &'static str, role: &'static str },
+    // rbp is neither lbp (left assoc) nor lbp - 1 (right assoc)
+    Associativity { name: &'static str, lbp: u8, rbp: u8 },
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Issue::MissingLed { name, lbp } =>
+                write!(f, "{} has lbp {} but no led", name, lbp),
+            Issue::PrefixWithLbp { name, lbp } =>
+                write!(f, "{} is prefix only but has lbp {}", name, lbp),
+            Issue::MissingEnd =>
+                write!(f, "no end token"),
+            Issue::Duplicate { name, role } =>
+                write!(f, "{} registered twice as {}", name, role),
+            Issue::Associativity { name, lbp, rbp } =>
+                write!(f, "{} parses its right operand with rbp {} against lbp {}: \
+                           neither left ({}) nor right ({}) associative",
+                       name, rbp, lbp, lbp, lbp.saturating_sub(1)),
+        }
+    }
+}
+
+
+#[derive(Clone, Default)]
+pub struct Grammar {
+    rules: Vec<Rule>,
+    index: HashMap<&'static str, usize>,
+    duplicates: Vec<Issue>,
+    // string interpolation delimiters, given to the tokenizers
+    interpolation: Option<(&'static str, &'static str)>,
+    layout: bool,
+    // the operator between adjacent operands, an infix rule of no token
+    juxtaposition: Option<Rule>,
+}
+
+impl Grammar {
+    pub fn new() -> Grammar {
+        Grammar::default()
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    pub fn rule(&self, name: &str) -> Option<&Rule> {
+        self.index.get(name).map(|&i| &self.rules[i])
+    }
+
+    pub fn juxtaposition_rule(&self) -> Option<&Rule> {
+        self.juxtaposition.as_ref()
+    }
+
+    fn rule_mut(&mut self, name: &'static str) -> &mut Rule {
+        let rules = &mut self.rules;
+        let i = *self.index.entry(name).or_insert_with(|| {
+            rules.push(Rule::new(name));
+            rules.len() - 1
+        });
+        &mut self.rules[i]
+    }
+
+    fn set_prefix(&mut self, name: &'static str, prefix: Prefix) {
+        if self.rule_mut(name).prefix.is_some() {
+            self.duplicates.push(Issue::Duplicate { name, role: "nud" });
+        }
+        self.rule_mut(name).prefix = Some(prefix);
+    }
+
+    fn set_infix(&mut self, name: &'static str, lbp: u8, infix: Infix) {
+        if self.rule_mut(name).infix.is_some() {
+            self.duplicates.push(Issue::Duplicate { name, role: "led" });
+        }
+        let rule = self.rule_mut(name);
+        rule.lbp = lbp;
+        rule.infix = Some(infix);
+    }
+
+    //  nud, led and lbp of 'name' at once, replacing earlier ones; what
+    //  end(), restrict() and the like set stays, and so do the rules that
+    //  group() and call() made for close and separator tokens
+    pub fn register(&mut self, name: &'static str, lbp: u8, prefix: Option<Prefix>, infix: Option<Infix>) {
+        let rule = self.rule_mut(name);
+        let duplicate = rule.prefix.is_some() || rule.infix.is_some();
+        rule.lbp = lbp;
+        rule.prefix = prefix;
+        rule.infix = infix;
+        if duplicate {
+            self.duplicates.push(Issue::Duplicate { name, role: "token" });
+        }
+    }
+
+    pub fn atom(&mut self, name: &'static str) {
+        self.set_prefix(name, Prefix::Atom);
+    }
+
+    pub fn end(&mut self, name: &'static str) {
+        let rule = self.rule_mut(name);
+        rule.end = true;
+    }
+
+    pub fn prefix(&mut self, name: &'static str, rbp: u8) {
+        self.set_prefix(name, Prefix::Operator { rbp });
+    }
+
+    pub fn infix(&mut self, name: &'static str, lbp: u8, assoc: Assoc) {
+        let rbp = match assoc {
+            Assoc::Left => lbp,
+            Assoc::Right => lbp.saturating_sub(1),
+        };
+        self.set_infix(name, lbp, Infix::Operator { rbp });
+    }
+
+    pub fn infix_rbp(&mut self, name: &'static str, lbp: u8, rbp: u8) {
+        self.set_infix(name, lbp, Infix::Operator { rbp });
+    }
+
+    pub fn postfix(&mut self, name: &'static str, lbp: u8) {
+        self.set_infix(name, lbp, Infix::Postfix);
+    }
+
+    pub fn group(&mut self, open: &'static str, close: &'static str) {
+        self.set_prefix(open, Prefix::Group { close });
+        self.rule_mut(close);
+    }
+
+    pub fn prefix_mixfix(&mut self, name: &'static str, parts: &[&'static str], rbp: u8) {
+        self.set_prefix(name, Prefix::Mixfix { parts: parts.to_vec(), rbp });
+        for part in parts {
+            self.rule_mut(part);
+        }
+    }
+
+    pub fn infix_mixfix(&mut self, name: &'static str, lbp: u8, parts: &[&'static str], rbp: u8) {
+        self.set_infix(name, lbp, Infix::Mixfix { parts: parts.to_vec(), rbp });
+        for part in parts {
+            self.rule_mut(part);
+        }
+    }
+
+    pub fn call(&mut self, open: &'static str, sep: &'static str, close: &'static str, lbp: u8) {
+        self.set_infix(open, lbp, Infix::Call { sep, close });
+        self.rule_mut(sep);
+        self.rule_mut(close);
+    }
+
+    //  tokenizers in layout mode: newline, indent and dedent end expressions,
+    //  nud/led of block constructs take them
+    pub fn layout(&mut self) {
+        self.layout = true;
+        self.rule_mut("newline");
+        self.rule_mut("indent");
+        self.rule_mut("dedent");
+    }
+
+    //  strings with expressions between 'open' and 'close': the node is the
+    //  string_head fragment, its children the expressions and the
+    //  string_middle and string_tail fragments after each of them
+    pub fn interpolation(&mut self, open: &'static str, close: &'static str) {
+        self.interpolation = Some((open, close));
+        self.rule_mut("string_middle");
+        self.rule_mut("string_tail");
+        self.nud("string_head", Rc::new(|se, this, pratt| {
+            loop {
+                add_child(se, pratt, pratt.parse(0));
+                let fragment = match pratt.current() {
+                    Some(t) => t,
+                    None => return this,
+                };
+                let kind = fragment.borrow_mut().lexeme().map(|l| l.kind.clone()).unwrap_or_default();
+                if pratt.error().is_some() || (kind != "string_middle" && kind != "string_tail") {
+                    let found = fragment.borrow_mut().token.code.clone();
+                    pratt.fail(ParseError::Syntax(format!("expected the rest of the string found {}", found)));
+                    return this;
+                }
+                pratt.advance();
+                add_child(se, pratt, fragment);
+                if kind == "string_tail" {
+                    return this;
+                }
+            }
+        }));
+    }
+
+    //  the led of 'name' only applies on the line of its left operand:
+    //  "a\n(b)" is not a call
+    pub fn restrict(&mut self, name: &'static str) {
+        self.rule_mut(name).restricted = true;
+    }
+
+    //  'name' with whitespace before and none after starts an operand, as
+    //  in Swift: "a -b" is 'a' then "-b", "a - b" and "a-b" subtract. The
+    //  expression ends before "-b", so without a juxtaposition to take it
+    //  as an argument "a -b" is a syntax error
+    pub fn spaced_prefix(&mut self, name: &'static str) {
+        self.rule_mut(name).spaced_prefix = true;
+    }
+
+    //  adjacent operands as an infix operator: "f x y" is
+    //  "(name (name f x) y)" when left associative; 'name' is only the
+    //  node's code, the lexer does not know it
+    pub fn juxtaposition(&mut self, name: &'static str, lbp: u8, assoc: Assoc) {
+        let rbp = match assoc {
+            Assoc::Left => lbp,
+            Assoc::Right => lbp.saturating_sub(1),
+        };
+        let mut rule = Rule::new(name);
+        rule.lbp = lbp;
+        rule.infix = Some(Infix::Operator { rbp });
+        self.juxtaposition = Some(rule);
+    }
+
+    pub fn nud(&mut self, name: &'static str, f: Fnud) {
+        self.set_prefix(name, Prefix::Custom(f));
+    }
+
+    pub fn led(&mut self, name: &'static str, lbp: u8, f: Fled) {
+        self.set_infix(name, lbp, Infix::Custom(f));
+    }
+
+    pub fn check(&self) -> Vec<Issue> {
+        let mut issues = self.duplicates.clone();
+        if !self.rules.iter().any(|r| r.end) {
+            issues.push(Issue::MissingEnd);
+        }
+        for r in &self.rules {
+            if r.infix.is_none() && r.lbp > 0 {
+                if r.prefix.is_some() {
+                    issues.push(Issue::PrefixWithLbp { name: r.name, lbp: r.lbp });
+                } else {
+                    issues.push(Issue::MissingLed { name: r.name, lbp: r.lbp });
+                }
+            }
+            if let Some(Infix::Operator { rbp }) | Some(Infix::Mixfix { rbp, .. }) = r.infix {
+                if r.assoc().is_none() {
+                    issues.push(Issue::Associativity { name: r.name, lbp: r.lbp, rbp });
+                }
+            }
+        }
+        issues
+    }
+
+    pub fn install(&self, tokenizer: &StringTokenizer<DynamicSymbol>) {
+        for rule in &self.rules {
+            let rule = rule.clone();
+            tokenizer.register_token(rule.name, Box::new(move |s| { rule.token(s) }));
+        }
+        if let Some((open, close)) = self.interpolation {
+            tokenizer.set_interpolation(open, close);
+        }
+        if self.layout {
+            tokenizer.set_layout(true);
+        }
+        if let Some(ref rule) = self.juxtaposition {
+            let rule = rule.clone();
+            tokenizer.set_juxtaposition(rule.name, Box::new(move |s| { rule.token(s) }));
+        }
+    }
+
+    pub fn tokenizer(&self, input: &str) -> StringTokenizer<DynamicSymbol> {
+        let tokenizer = StringTokenizer::new(String::from(input));
+        self.install(&tokenizer);
+        tokenizer
+    }
+
+    pub fn parser(&self, input: &str) -> Pratt<DynamicSymbol, StringTokenizer<DynamicSymbol>> {
+        Pratt::new(self.tokenizer(input))
+    }
+
+    //  numbers, names and strings with + - * / ^, unary minus and parentheses
+    pub fn arithmetic() -> Grammar {
+        let mut g = Grammar::new();
+        g.atom("num");
+        g.atom("literal");
+        g.atom("string");
+        g.end("end");
+        g.infix("+", 20, Assoc::Left);
+        g.infix("-", 20, Assoc::Left);
+        g.infix("*", 30, Assoc::Left);
+        g.infix("/", 30, Assoc::Left);
+        g.infix("^", 40, Assoc::Right);
+        g.prefix("-", 50);
+        g.group("(", ")");
+        g
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use super::{Symbol, Pratt, ParseError};
+use super::lexer::{Lexeme, StringTokenizer};
+use super::events::{self, Sink};
+
+//  compact, immutable syntax tree: nodes only know their kind, their
+//  length in bytes and their children, never where they are, so equal
+//  subtrees can be shared; tokens keep their trivia in their text
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct GreenToken {
+    kind: Arc<str>,
+    text: String,
+    // bytes of leading and trailing trivia in 'text'
+    leading: usize,
+    trailing: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GreenElement {
+    Node(Arc<GreenNode>),
+    Token(Arc<GreenToken>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GreenNode {
+    kind: Arc<str>,
+    len: usize,
+    children: Vec<GreenElement>,
+}
+
+impl GreenToken {
+    pub fn new(kind: Arc<str>, lexeme: &Lexeme) -> GreenToken {
+        GreenToken {
+            kind,
+            text: lexeme.full_text(),
+            leading: lexeme.leading.len(),
+            trailing: lexeme.trailing.len(),
+        }
+    }
+    pub fn kind(&self) -> &str {
+        &self.kind
+    }
+    //  the token itself, without trivia
+    pub fn text(&self) -> &str {
+        &self.text[self.leading..self.text.len() - self.trailing]
+    }
+    pub fn full_text(&self) -> &str {
+        &self.text
+    }
+    pub fn leading(&self) -> usize {
+        self.leading
+    }
+    pub fn len(&self) -> usize {
+        self.text.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+}
+
+impl GreenElement {
+    pub fn kind(&self) -> &str {
+        match *self {
+            GreenElement::Node(ref n) => n.kind(),
+            GreenElement::Token(ref t) => t.kind(),
+        }
+    }
+    pub fn len(&self) -> usize {
+        match *self {
+            GreenElement::Node(ref n) => n.len(),
+            GreenElement::Token(ref t) => t.len(),
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl GreenNode {
+    pub fn new(kind: Arc<str>, children: Vec<GreenElement>) -> GreenNode {
+        let len = children.iter().map(|c| c.len()).sum();
+        GreenNode { kind, len, children }
+    }
+    pub fn kind(&self) -> &str {
+        &self.kind
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn children(&self) -> &[GreenElement] {
+        &self.children
+    }
+
+    //  a copy with child 'index' replaced, the other children are shared
+    pub fn replace_child(&self, index: usize, child: GreenElement) -> GreenNode {
+        let mut children = self.children.clone();
+        children[index] = child;
+        GreenNode::new(self.kind.clone(), children)
+    }
+
+    pub fn text(&self) -> String {
+        let mut out = String::with_capacity(self.len);
+        self.write(&mut out);
+        out
+    }
+
+    fn write(&self, out: &mut String) {
+        for child in &self.children {
+            match *child {
+                GreenElement::Node(ref n) => n.write(out),
+                GreenElement::Token(ref t) => out.push_str(&t.text),
+            }
+        }
+    }
+}
+
+
+//  kinds, tokens and small nodes already built, so equal ones are shared;
+//  keep it between parses of the same text to share across versions
+#[derive(Default)]
+pub struct NodeCache {
+    kinds: HashMap<String, Arc<str>>,
+    tokens: HashMap<GreenToken, Arc<GreenToken>>,
+    // children are shared already, so they are told apart by address
+    nodes: HashMap<(Arc<str>, Vec<usize>), Arc<GreenNode>>,
+}
+
+//  bigger nodes are rarely equal, hashing them is not worth it
+const CACHED_CHILDREN: usize = 3;
+
+impl NodeCache {
+    pub fn new() -> NodeCache {
+        NodeCache::default()
+    }
+
+    pub fn kind(&mut self, kind: &str) -> Arc<str> {
+        if let Some(k) = self.kinds.get(kind) {
+            return k.clone();
+        }
+        let k: Arc<str> = Arc::from(kind);
+        self.kinds.insert(String::from(kind), k.clone());
+        k
+    }
+
+    pub fn token(&mut self, kind: &str, lexeme: &Lexeme) -> Arc<GreenToken> {
+        let token = GreenToken::new(self.kind(kind), lexeme);
+        self.tokens.entry(token.clone()).or_insert_with(|| Arc::new(token)).clone()
+    }
+
+    //  drops the tokens and nodes only the cache holds, e.g. those of old
+    //  versions of a tree, so that it does not grow with every edit
+    pub fn evict(&mut self) {
+        loop {
+            let len = self.len();
+            self.nodes.retain(|_, n| Arc::strong_count(n) > 1);
+            self.tokens.retain(|_, t| Arc::strong_count(t) > 1);
+            // the children of a dropped node may be held by the cache alone now
+            if self.len() == len {
+                break;
+            }
+        }
+    }
+
+    //  tokens and nodes held
+    pub fn len(&self) -> usize {
+        self.tokens.len() + self.nodes.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn node(&mut self, kind: &str, children: Vec<GreenElement>) -> Arc<GreenNode> {
+        let kind = self.kind(kind);
+        if children.len() > CACHED_CHILDREN {
+            return Arc::new(GreenNode::new(kind, children));
+        }
+        let key = children.iter().map(|c| match *c {
+            GreenElement::Node(ref n) => &**n as *const GreenNode as usize,
+            GreenElement::Token(ref t) => &**t as *const GreenToken as usize,
+        }).collect();
+        self.nodes.entry((kind.clone(), key)).or_insert_with(|| Arc::new(GreenNode::new(kind, children))).clone()
+    }
+}
+
+
+//  a Sink that builds a green tree
+#[derive(Default)]
+pub struct GreenBuilder {
+    cache: NodeCache,
+    // the children of the open nodes, one after the other
+    children: Vec<GreenElement>,
+    // kind and first child of each open node
+    parents: Vec<(String, usize)>,
+    root: Option<Arc<GreenNode>>,
+}
+
+impl GreenBuilder {
+    pub fn new() -> GreenBuilder {
+        GreenBuilder::default()
+    }
+
+    pub fn with_cache(cache: NodeCache) -> GreenBuilder {
+        GreenBuilder { cache, children: vec![], parents: vec![], root: None }
+    }
+
+    pub fn finish(self) -> Option<Arc<GreenNode>> {
+        self.root
+    }
+
+    pub fn finish_with_cache(self) -> (Option<Arc<GreenNode>>, NodeCache) {
+        (self.root, self.cache)
+    }
+}
+
+impl Sink for GreenBuilder {
+    fn start_node(&mut self, kind: &str) {
+        self.parents.push((String::from(kind), self.children.len()));
+    }
+    fn token(&mut self, lexeme: Lexeme) {
+        let token = GreenElement::Token(self.cache.token(&lexeme.kind, &lexeme));
+        self.children.push(token);
+    }
+    fn finish_node(&mut self) {
+        let (kind, first) = self.parents.pop().unwrap();
+        let children = self.children.split_off(first);
+        let node = self.cache.node(&kind, children);
+        if self.parents.is_empty() {
+            self.root = Some(node);
+        } else {
+            self.children.push(GreenElement::Node(node));
+        }
+    }
+    fn checkpoint(&self) -> usize {
+        self.children.len()
+    }
+    fn start_node_at(&mut self, checkpoint: usize, kind: &str) {
+        self.parents.push((String::from(kind), checkpoint));
+    }
+}
+
+//  parses the tokenizer's input, every expression of it, into a green
+//  tree, turning on lossless mode
+pub fn parse<S: Symbol + 'static>(tokenizer: StringTokenizer<S>) -> Result<Arc<GreenNode>, ParseError> {
+    tokenizer.set_lossless(true);
+    let builder = events::parse_all(Pratt::new(tokenizer), GreenBuilder::new())?;
+    Ok(builder.finish().unwrap())
+}
@@ -0,0 +1,301 @@
+use std::mem;
+use std::ops::Range;
+use std::rc::Rc;
+use std::sync::Arc;
+use super::{Pratt, ParseError, Symbol};
+use super::dyn::DynamicSymbol;
+use super::events::EventTracer;
+use super::grammar::{Grammar, Prefix, Rule};
+use super::green::{GreenBuilder, GreenElement, GreenNode, NodeCache};
+use super::lexer::{Lexeme, LexState, StringTokenizer};
+pub use super::lexer::Edit;
+use super::red::{SyntaxElement, SyntaxNode};
+
+//  keeps the green tree of a text, every expression of it, up to date
+//  across edits: the tokens are relexed from the edit on, then only the
+//  smallest group "( ... )" or run of top-level expressions around the
+//  tokens that changed is reparsed, lexing from the state before it; the
+//  rest of the tree is shared with the previous version. Whatever cannot
+//  be reparsed on its own, or does not come out as the tokens it was
+//  lexed to, falls back to parsing the whole text. The
+//  grammar's nud/led must not switch lexer modes, the tokens are lexed
+//  without them
+
+pub struct Document {
+    grammar: Grammar,
+    text: String,
+    tokens: Vec<(Lexeme, LexState)>,
+    root: Arc<GreenNode>,
+    cache: NodeCache,
+    // the first syntax error of the text, see edit()
+    error: Option<ParseError>,
+}
+
+type Parser = Pratt<DynamicSymbol, StringTokenizer<DynamicSymbol>>;
+
+//  the tokens an edit changed: old[start..old_end] became new[start..new_end]
+struct Changed {
+    start: usize,
+    old_end: usize,
+    new_end: usize,
+}
+
+impl Changed {
+    fn new(old: &[(Lexeme, LexState)], new: &[(Lexeme, LexState)], delta: isize) -> Changed {
+        let start = old.iter().zip(new).take_while(|(o, n)| o.0 == n.0).count();
+        let same = old[start..].iter().rev().zip(new[start..].iter().rev())
+            .take_while(|(o, n)| o.0.shifted(delta) == n.0)
+            .count();
+        Changed { start, old_end: old.len() - same, new_end: new.len() - same }
+    }
+
+    //  where old token 'i' past the change is in the new tokens
+    fn shift(&self, i: usize) -> usize {
+        i + self.new_end - self.old_end
+    }
+}
+
+impl Document {
+    pub fn new(grammar: &Grammar, text: &str) -> Result<Document, ParseError> {
+        let mut cache = NodeCache::new();
+        let (root, error) = parse(grammar, text, &mut cache);
+        if let Some(err) = error {
+            return Err(err);
+        }
+        let tokens = lexer(grammar, text).lex();
+        Ok(Document { grammar: grammar.clone(), text: String::from(text), tokens, root, cache, error: None })
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+    pub fn green(&self) -> &Arc<GreenNode> {
+        &self.root
+    }
+    pub fn tree(&self) -> SyntaxNode {
+        SyntaxNode::new_root(self.root.clone())
+    }
+    pub fn error(&self) -> Option<&ParseError> {
+        self.error.as_ref()
+    }
+
+    //  applies the edit and returns the range of the new text that was
+    //  reparsed. A syntax error is returned, and kept until an edit fixes
+    //  it, but the edit still applies: the tree is the one the parse made
+    //  going on past the error, and the whole text is parsed again while
+    //  the error stays
+    pub fn edit(&mut self, edit: &Edit) -> Result<Range<usize>, ParseError> {
+        let text = edit.apply(&self.text);
+        let tokens = lexer(&self.grammar, &text).relex(&self.tokens, edit);
+        let changed = Changed::new(&self.tokens, &tokens, edit.delta());
+        let reparsed = match self.error {
+            Some(_) => None,
+            None => match self.reparse_group(&text, &tokens, &changed) {
+                Some(reparsed) => Some(reparsed),
+                None => self.reparse_expressions(&text, &tokens, &changed),
+            },
+        };
+        let range = match reparsed {
+            Some((root, range)) => {
+                self.root = root;
+                range
+            }
+            None => {
+                let (root, error) = parse(&self.grammar, &text, &mut self.cache);
+                self.root = root;
+                self.error = error;
+                0..text.len()
+            }
+        };
+        self.text = text;
+        self.tokens = tokens;
+        // the nodes of the old tree that the new one does not share
+        self.cache.evict();
+        match self.error {
+            Some(ref err) => Err(err.clone()),
+            None => Ok(range),
+        }
+    }
+
+    //  the deepest group whose delimiters are before and after the changed
+    //  tokens, parsed on its own from its open token
+    fn reparse_group(&mut self, text: &str, tokens: &[(Lexeme, LexState)], changed: &Changed) -> Option<(Arc<GreenNode>, Range<usize>)> {
+        // the nodes around the change, with the index of their first token
+        let mut around = vec![];
+        let mut node = self.tree();
+        let mut first = 0;
+        'down: loop {
+            let mut i = first;
+            for element in node.children_with_tokens() {
+                let child = match element {
+                    SyntaxElement::Node(child) => child,
+                    SyntaxElement::Token(_) => {
+                        i += 1;
+                        continue;
+                    }
+                };
+                let len = count(child.green());
+                if i < changed.start && i + len > changed.old_end {
+                    around.push((child.clone(), i, len));
+                    node = child;
+                    first = i;
+                    continue 'down;
+                }
+                i += len;
+            }
+            break;
+        }
+        for (node, first, len) in around.into_iter().rev() {
+            if !self.is_group(&node) {
+                continue;
+            }
+            let last = changed.shift(first + len - 1);
+            let parsed = self.parse_at(text, tokens, first, |pratt| {
+                pratt.advance();
+                // no led binds that tight: the group and nothing after it
+                pratt.parse(u8::MAX);
+                pratt.error().is_none()
+            }).map(|(parsed, _)| parsed);
+            let group = match parsed.as_ref().and_then(|p| p.first()) {
+                Some(GreenElement::Node(group)) if group.kind() == node.kind() && count(group) == last + 1 - first => group.clone(),
+                _ => continue,
+            };
+            return Some((node.replace_with(group), span(text, tokens, first..last + 1)));
+        }
+        None
+    }
+
+    //  a node made by the nud of a group token, not by a led it is in
+    fn is_group(&self, node: &SyntaxNode) -> bool {
+        let close = match self.grammar.rule(node.kind()) {
+            Some(&Rule { prefix: Some(Prefix::Group { close }), .. }) => close,
+            _ => return false,
+        };
+        let children = node.children_with_tokens();
+        match (children.first(), children.last()) {
+            (Some(SyntaxElement::Token(open)), Some(SyntaxElement::Token(last))) =>
+                open.kind() == node.kind() && last.kind() == close,
+            _ => false,
+        }
+    }
+
+    //  the top-level expressions from the last end token before the change
+    //  to the first one after it
+    fn reparse_expressions(&mut self, text: &str, tokens: &[(Lexeme, LexState)], changed: &Changed) -> Option<(Arc<GreenNode>, Range<usize>)> {
+        // child and token index where each expression starts, then the end of input
+        let mut starts = vec![(0, 0)];
+        let mut i = 0;
+        for (c, child) in self.root.children().iter().enumerate() {
+            match *child {
+                GreenElement::Node(ref n) => i += count(n),
+                GreenElement::Token(ref t) => {
+                    i += 1;
+                    if self.grammar.rule(t.kind()).is_some_and(|r| r.end) {
+                        starts.push((c + 1, i));
+                    }
+                }
+            }
+        }
+        // the end of input only starts an expression after an end token
+        let ended = starts.len();
+        if starts.last() != Some(&(self.root.children().len(), i)) {
+            starts.push((self.root.children().len(), i));
+        }
+        let &(from, first) = starts[..ended].iter().rev().find(|&&(_, t)| t <= changed.start)?;
+        let &(to, end) = starts.iter().find(|&&(_, t)| t > changed.old_end || t == i)?;
+        if from == 0 && to == self.root.children().len() {
+            return None;
+        }
+        let end = changed.shift(end);
+        let last = tokens.get(end.wrapping_sub(1)).map(|(l, _)| l.span.clone());
+        let (parsed, state) = self.parse_at(text, tokens, first, |pratt| {
+            for result in pratt.expressions() {
+                if result.is_err() {
+                    return false;
+                }
+                let at = pratt.current().and_then(|t| t.borrow_mut().lexeme().map(|l| l.span.clone()));
+                if end < tokens.len() && at == last {
+                    return true;
+                }
+            }
+            end == tokens.len()
+        })?;
+        // it has to end where the old expressions did: on the same token,
+        // lexed to the same state, unless the lexer went on to the end of input
+        if tokens_in(&parsed) != end - first || (end > first && end < tokens.len() && state != tokens[end - 1].1) {
+            return None;
+        }
+        let mut children = self.root.children()[..from].to_vec();
+        children.extend(parsed);
+        children.extend_from_slice(&self.root.children()[to..]);
+        Some((self.cache.node("root", children), span(text, tokens, first..end)))
+    }
+
+    //  the top-level nodes and tokens 'parse' makes from token 'first' on,
+    //  lexed from the state before it, and the lexer's state after them;
+    //  None when 'parse' fails
+    fn parse_at<F: FnOnce(&Parser) -> bool>(&mut self, text: &str, tokens: &[(Lexeme, LexState)], first: usize, parse: F) -> Option<(Vec<GreenElement>, LexState)> {
+        let tokenizer = lexer(&self.grammar, text);
+        if first > 0 {
+            tokenizer.restore(tokens[first - 1].1.clone());
+        }
+        let mut pratt = Pratt::new(tokenizer);
+        pratt.set_build_tree(false);
+        let builder = GreenBuilder::with_cache(mem::replace(&mut self.cache, NodeCache::new()));
+        let tracer = Rc::new(EventTracer::new(builder));
+        pratt.set_tracer(tracer.clone());
+        let ok = parse(&pratt);
+        let state = pratt.into_tokenizer().state();
+        let (root, cache) = tracer.finish().finish_with_cache();
+        self.cache = cache;
+        if !ok {
+            return None;
+        }
+        Some((root?.children().to_vec(), state))
+    }
+}
+
+//  the text of tokens[range], their trivia included
+fn span(text: &str, tokens: &[(Lexeme, LexState)], range: Range<usize>) -> Range<usize> {
+    let start = tokens.get(range.start).map_or(text.len(), |(l, _)| l.span.start - l.leading.len());
+    if range.end <= range.start {
+        return start..start;
+    }
+    let last = &tokens[range.end - 1].0;
+    start..last.span.end + last.trailing.len()
+}
+
+fn count(node: &GreenNode) -> usize {
+    tokens_in(node.children())
+}
+
+fn tokens_in(elements: &[GreenElement]) -> usize {
+    elements.iter().map(|c| match *c {
+        GreenElement::Node(ref n) => count(n),
+        GreenElement::Token(_) => 1,
+    }).sum()
+}
+
+fn lexer(grammar: &Grammar, text: &str) -> StringTokenizer<DynamicSymbol> {
+    let tokenizer = grammar.tokenizer(text);
+    tokenizer.set_lossless(true);
+    tokenizer
+}
+
+//  every expression of 'text' and the first syntax error; the parse goes
+//  on after errors, so the tree holds all of the text
+fn parse(grammar: &Grammar, text: &str, cache: &mut NodeCache) -> (Arc<GreenNode>, Option<ParseError>) {
+    let mut pratt = Pratt::new(lexer(grammar, text));
+    pratt.set_build_tree(false);
+    let tracer = Rc::new(EventTracer::new(GreenBuilder::with_cache(mem::replace(cache, NodeCache::new()))));
+    pratt.set_tracer(tracer.clone());
+    let mut error = None;
+    for result in pratt.expressions() {
+        if let Err(err) = result {
+            error = error.or(Some(err));
+        }
+    }
+    let (root, used) = tracer.finish().finish_with_cache();
+    *cache = used;
+    (root.unwrap(), error)
+}
@@ -0,0 +1,1080 @@
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufRead};
+use std::ops::Range;
+use std::str;
+use std::iter;
+use super::{PrattBox, Symbol, Tokenizer, CancelHandle};
+
+pub type FnewToken<S> = Box<dyn Fn(&str) -> PrattBox<S>>;
+//  for a lexer mode: the length in bytes and the registered token type of
+//  the token at the start of the input, None to lex it as usual
+pub type FnScan = Box<dyn Fn(&str) -> Option<(usize, &'static str)>>;
+
+//  where a token came from: the name it was made with ("num", "literal", "+"),
+//  its exact source text and byte span; in lossless mode also the trivia
+//  around it, the trailing one runs up to and including the end of line
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Lexeme {
+    pub kind: String,
+    pub text: String,
+    pub span: Range<usize>,
+    pub leading: String,
+    pub trailing: String,
+    // a line break between the token before and this one
+    pub newline_before: bool,
+    // whitespace or a comment right before and right after the token
+    pub space_before: bool,
+    pub space_after: bool,
+}
+
+impl Lexeme {
+    //  the source text the lexeme covers, trivia included
+    pub fn full_text(&self) -> String {
+        format!("{}{}{}", self.leading, self.text, self.trailing)
+    }
+
+    //  the code the token was made from: strings lose their closing
+    //  quote, quoted words their opening one
+    pub fn code(&self) -> &str {
+        if self.kind == "string" {
+            if self.text.starts_with('\'') {
+                return &self.text[1..];
+            }
+            if self.text.len() > 1 && self.text.ends_with('"') {
+                return &self.text[..self.text.len() - 1];
+            }
+        }
+        &self.text
+    }
+
+    pub(crate) fn shifted(&self, delta: isize) -> Lexeme {
+        let mut lexeme = self.clone();
+        lexeme.span = shift(self.span.start, delta)..shift(self.span.end, delta);
+        lexeme
+    }
+}
+
+//  the char at byte 'j', empty at the end of input; the state machine
+//  steps over whole chars so that it never slices one apart
+fn char_at(input: &str, j: usize) -> &str {
+    let len = input[j..].chars().next().map_or(0, char::len_utf8);
+    &input[j..j + len]
+}
+
+fn shift(pos: usize, delta: isize) -> usize {
+    (pos as isize + delta) as usize
+}
+
+//  a text edit: 'range' of the old text replaced by 'insert'
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edit {
+    pub range: Range<usize>,
+    pub insert: String,
+}
+
+impl Edit {
+    pub fn new(range: Range<usize>, insert: &str) -> Edit {
+        Edit { range, insert: String::from(insert) }
+    }
+
+    pub fn apply(&self, text: &str) -> String {
+        format!("{}{}{}", &text[..self.range.start], self.insert, &text[self.range.end..])
+    }
+
+    //  bytes added by the edit, negative when it removes more than it inserts
+    pub fn delta(&self) -> isize {
+        self.insert.len() as isize - self.range.len() as isize
+    }
+}
+   
+#[derive(Debug, Clone, PartialEq)]
+enum TokenStatus {
+    Init,
+    InToken,
+    InQuote,
+    InString,
+    InNum,
+    EndToken,
+}
+ 
+#[derive(Debug, Clone, PartialEq)]
+struct TokenizerStatus {
+    status: TokenStatus,
+    i: usize,
+    j: usize,
+    inquotepar: u32,
+    // end of the trivia already given to a lexeme
+    trivia: usize,
+    // pushed by the parser, the last one is in effect
+    modes: Vec<&'static str>,
+    // interpolations opened in strings and not closed yet, with the
+    // brackets opened in each of them
+    holes: Vec<u32>,
+    layout: Option<Layout>,
+    // a line break, any trivia since the last token
+    line: bool,
+    space: bool,
+}
+
+//  indentation in layout mode
+#[derive(Debug, Clone, PartialEq)]
+struct Layout {
+    // columns of the open blocks, 0 at the bottom
+    indents: Vec<usize>,
+    // open brackets, the lines inside them are joined
+    depth: u32,
+    // a line ended since the last token, the next one is 'column' in
+    newline: bool,
+    column: usize,
+    // newline, indent and dedent tokens still to make
+    queue: VecDeque<&'static str>,
+    started: bool,
+    after_newline: bool,
+    // the end of input was seen
+    closed: bool,
+}
+
+impl Layout {
+    fn new() -> Layout {
+        Layout {
+            indents: vec![0], depth: 0, newline: false, column: 0,
+            queue: VecDeque::new(), started: false, after_newline: false, closed: false,
+        }
+    }
+
+    fn space(&mut self, c: &str) {
+        match c {
+            "\n" => {
+                self.newline = self.depth == 0 && self.started;
+                self.column = 0;
+            }
+            "\t" => self.column = (self.column / 8 + 1) * 8,
+            _ => self.column += 1,
+        }
+    }
+
+    fn made(&mut self, kind: &str, code: &str) {
+        self.started = true;
+        self.after_newline = kind == "newline";
+        match code {
+            "(" | "[" | "{" => self.depth += 1,
+            ")" | "]" | "}" => self.depth = self.depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    //  the tokens before the first one of a line, false on a dedent
+    //  to a column no block started at
+    fn line(&mut self) -> bool {
+        self.newline = false;
+        self.queue.push_back("newline");
+        if self.column > *self.indents.last().unwrap() {
+            self.indents.push(self.column);
+            self.queue.push_back("indent");
+            return true;
+        }
+        while self.column < *self.indents.last().unwrap() {
+            self.indents.pop();
+            self.queue.push_back("dedent");
+        }
+        self.column == *self.indents.last().unwrap()
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        if !self.after_newline {
+            self.queue.push_back("newline");
+        }
+        for _ in 1..self.indents.len() {
+            self.queue.push_back("dedent");
+        }
+        self.indents.truncate(1);
+    }
+}
+
+impl TokenizerStatus {
+    fn start() -> TokenizerStatus {
+        TokenizerStatus { status: TokenStatus::Init, i: 0, j: 0, inquotepar: 0, trivia: 0, modes: vec![], holes: vec![], layout: None, line: false, space: false }
+    }
+}
+
+//  where the tokenizer is and in which mode, to restart it there later
+#[derive(Debug, Clone, PartialEq)]
+pub struct LexState(TokenizerStatus);
+
+impl LexState {
+    fn shifted(&self, delta: isize) -> LexState {
+        let mut st = self.0.clone();
+        st.i = shift(st.i, delta);
+        st.j = shift(st.j, delta);
+        st.trivia = shift(st.trivia, delta);
+        LexState(st)
+    }
+}
+
+//  input read on demand, in chunks of at most CHUNK bytes
+struct Source {
+    reader: Box<dyn BufRead>,
+    // the start of a char split between two chunks
+    bytes: Vec<u8>,
+    error: Option<io::Error>,
+}
+
+const CHUNK: usize = 8192;
+
+//  a checkpoint: the current token is lexed again on rewind, so nodes
+//  made from it and the tokens after it by a failed attempt are dropped
+struct Snapshot {
+    base: usize,
+    state: TokenizerStatus,
+    // tokens made before the current one
+    made: usize,
+    current: bool,
+    exhausted: bool,
+}
+
+pub struct StringTokenizer<S: Symbol> {
+    // the text from 'base' on; positions in the status are relative to it
+    input: RefCell<String>,
+    base: Cell<usize>,
+    source: RefCell<Option<Source>>,
+    made: Cell<usize>,
+    exhausted: Cell<bool>,
+    tokens : RefCell<Vec<PrattBox<S>>>,
+    // tokens made by peek() after the current one, at the end of 'tokens'
+    ahead: Cell<usize>,
+    // the state before each of the current and peeked tokens, and its base
+    before: RefCell<VecDeque<(usize, TokenizerStatus)>>,
+    map: RefCell<HashMap<&'static str, FnewToken<S>>>,
+    scanners: RefCell<HashMap<&'static str, FnScan>>,
+    st: RefCell<TokenizerStatus>,
+    cancel: RefCell<Option<CancelHandle>>,
+    lossless: Cell<bool>,
+    comment: RefCell<Option<String>>,
+    interpolation: RefCell<Option<(String, String)>>,
+    // kind and factory of the operator between adjacent operands
+    juxtaposition: RefCell<Option<(&'static str, FnewToken<S>)>>,
+    layout_error: RefCell<Option<String>>,
+    // for the parser, see take_error()
+    pending: RefCell<Option<String>>,
+    last: RefCell<Lexeme>,
+}
+
+impl<S: Symbol> StringTokenizer<S> {
+    pub fn new(input: String) -> StringTokenizer<S> {
+        StringTokenizer {
+            input: RefCell::new(input),
+            base: Cell::new(0),
+            source: RefCell::new(None),
+            made: Cell::new(0),
+            exhausted: Cell::new(false),
+            st: RefCell::new(TokenizerStatus::start()),
+            tokens: RefCell::new(Vec::new()),
+            ahead: Cell::new(0),
+            before: RefCell::new(VecDeque::new()),
+            map: RefCell::new(HashMap::new()),
+            scanners: RefCell::new(HashMap::new()),
+            cancel: RefCell::new(None),
+            lossless: Cell::new(false),
+            comment: RefCell::new(None),
+            interpolation: RefCell::new(None),
+            juxtaposition: RefCell::new(None),
+            layout_error: RefCell::new(None),
+            pending: RefCell::new(None),
+            last: RefCell::new(Lexeme::default()),
+        }
+    }
+    //  kinds with no factory of their own, e.g. layout tokens, are made by
+    //  the "literal" one; without that the token is left out and the
+    //  parser gets an error
+    fn new_token(&self, typ: &str, s: &str) -> Option<PrattBox<S>> {
+        let map = self.map.borrow();
+        match map.get(typ).or_else(|| map.get("literal")) {
+            Some(f) => Some(f(s)),
+            None => {
+                self.fail(format!("no token registered for {}", typ));
+                None
+            }
+        }
+    }
+
+    fn fail(&self, msg: String) {
+        let mut pending = self.pending.borrow_mut();
+        if pending.is_none() {
+            *pending = Some(msg);
+        }
+    }
+
+    //  reads from 'reader' while lexing: only the current token and what
+    //  is left of the last chunk are kept, the parser gets the tokens one by
+    //  one and current() is the only one the tokenizer holds on to
+    pub fn from_reader<R: BufRead + 'static>(reader: R) -> StringTokenizer<S> {
+        let tokenizer = StringTokenizer::new(String::new());
+        *tokenizer.source.borrow_mut() = Some(Source { reader: Box::new(reader), bytes: vec![], error: None });
+        tokenizer
+    }
+
+    //  the read error that ended the input early, if any
+    pub fn io_error(&self) -> Option<io::Error> {
+        self.source.borrow_mut().as_mut().and_then(|s| s.error.take())
+    }
+
+    //  appends the next chunk of the reader to the input, false at its end
+    fn more(&self) -> bool {
+        let mut source = self.source.borrow_mut();
+        let source = match *source {
+            Some(ref mut source) => source,
+            None => return false,
+        };
+        let n = match source.reader.fill_buf() {
+            Ok(buf) => {
+                let n = buf.len().min(CHUNK);
+                source.bytes.extend_from_slice(&buf[..n]);
+                n
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => return true,
+            Err(e) => {
+                source.error = Some(e);
+                0
+            }
+        };
+        source.reader.consume(n);
+        let mut input = self.input.borrow_mut();
+        if n == 0 {
+            // what is left cannot become a char any more
+            input.push_str(&String::from_utf8_lossy(&source.bytes));
+            source.bytes.clear();
+            return false;
+        }
+        let valid = match str::from_utf8(&source.bytes) {
+            Ok(s) => s.len(),
+            Err(e) => e.valid_up_to(),
+        };
+        input.push_str(str::from_utf8(&source.bytes[..valid]).unwrap());
+        source.bytes.drain(..valid);
+        true
+    }
+
+    //  reads until the input goes past 'k' or ends
+    fn fill(&self, k: usize) {
+        while self.input.borrow().len() <= k && self.more() {}
+    }
+
+    //  chars the state machine looks at from 'j' on: the char after it
+    //  and a comment start
+    fn lookahead(&self) -> usize {
+        let hole = self.interpolation.borrow().as_ref().map_or(0, |(open, close)| open.len().max(close.len()));
+        2 + self.comment.borrow().as_ref().map_or(0, |c| c.len()).max(hole)
+    }
+
+    //  made by the factory registered as 'typ' from 'code', 'span' is the
+    //  source it covers (strings keep their closing quote out of the code);
+    //  the trailing trivia is added by trail() once the token is complete
+    fn emit(&self, st: &mut TokenizerStatus, input: &str, typ: &str, code: &str, span: Range<usize>) {
+        let token = match self.new_token(typ, code) {
+            Some(token) => token,
+            None => return,
+        };
+        let base = self.base.get();
+        let mut lexeme = Lexeme {
+            kind: String::from(typ),
+            text: String::from(&input[span.clone()]),
+            span: base + span.start..base + span.end,
+            leading: String::new(),
+            trailing: String::new(),
+            newline_before: st.line,
+            space_before: st.space,
+            space_after: self.trivia_end(input, span.end, false) > span.end,
+        };
+        st.line = false;
+        st.space = false;
+        if self.lossless.get() {
+            lexeme.leading = String::from(&input[st.trivia..span.start]);
+            st.trivia = span.end;
+        }
+        if let Some(ref mut layout) = st.layout {
+            layout.made(typ, code);
+        }
+        if let Some(l) = token.borrow_mut().lexeme() {
+            *l = lexeme.clone();
+        }
+        *self.last.borrow_mut() = lexeme;
+        let mut tokens = self.tokens.borrow_mut();
+        tokens.push(token);
+        if self.source.borrow().is_some() {
+            let n = tokens.len();
+            tokens.drain(..n - 1 - self.ahead.get());
+        }
+        self.made.set(self.made.get() + 1);
+    }
+
+    //  the trivia after the token just made, up to and including the end of
+    //  line, or up to the end of input when only trivia is left
+    fn trail(&self, st: &mut TokenizerStatus) {
+        let from = st.trivia;
+        loop {
+            let input = self.input.borrow();
+            if self.trivia_end(&input, from, false) < input.len() {
+                break;
+            }
+            drop(input);
+            if !self.more() {
+                break;
+            }
+        }
+        let input = self.input.borrow();
+        let mut end = self.trivia_end(&input, from, true);
+        if self.trivia_end(&input, end, false) == input.len() {
+            end = input.len();
+        }
+        let trailing = String::from(&input[from..end]);
+        st.trivia = end;
+        if let Some(token) = self.tokens.borrow().last() {
+            if let Some(l) = token.borrow_mut().lexeme() {
+                l.trailing = trailing.clone();
+            }
+        }
+        self.last.borrow_mut().trailing = trailing;
+    }
+
+    //  forgets the input before the next token and its leading trivia
+    fn compact(&self, st: &mut TokenizerStatus) {
+        let cut = if self.lossless.get() { st.trivia.min(st.i) } else { st.i };
+        if cut < CHUNK {
+            return;
+        }
+        self.input.borrow_mut().drain(..cut);
+        self.base.set(self.base.get() + cut);
+        st.i -= cut;
+        st.j -= cut;
+        st.trivia = st.trivia.saturating_sub(cut);
+    }
+
+    //  the registered factory for 'code' if any, else 'fallback'
+    fn kind_of(&self, code: &str, fallback: &'static str) -> String {
+        if self.map.borrow().contains_key(code) {
+            String::from(code)
+        } else {
+            String::from(fallback)
+        }
+    }
+
+    fn at_comment(&self, input: &str, j: usize) -> bool {
+        match *self.comment.borrow() {
+            Some(ref c) => input[j..].starts_with(c.as_str()),
+            None => false,
+        }
+    }
+
+    fn trivia_end(&self, input: &str, from: usize, line: bool) -> usize {
+        let mut k = from;
+        while k < input.len() {
+            match char_at(input, k) {
+                " " | "\t" => { k += 1; }
+                "\n" => {
+                    k += 1;
+                    if line {
+                        break;
+                    }
+                }
+                _ if self.at_comment(input, k) => {
+                    k += input[k..].find('\n').unwrap_or(input.len() - k);
+                }
+                _ => break,
+            }
+        }
+        k
+    }
+
+    //  keep whitespace and comments on the lexemes of the tokens,
+    //  so the tokens print back to the exact input
+    pub fn set_lossless(&self, lossless: bool) {
+        self.lossless.set(lossless);
+    }
+
+    //  skip from 'start' to the end of line between tokens
+    pub fn set_line_comment(&self, start: &str) {
+        *self.comment.borrow_mut() = Some(String::from(start));
+    }
+
+    pub fn register_token(& self, s: &'static str, f: FnewToken<S>) {
+        self.map.borrow_mut().insert(s, f);
+    }
+
+    //  the parser's operator between adjacent operands, made by 'f' from
+    //  'kind'; it is not lexed, so 'kind' can also be a word of the input
+    pub fn set_juxtaposition(&self, kind: &'static str, f: FnewToken<S>) {
+        *self.juxtaposition.borrow_mut() = Some((kind, f));
+    }
+
+    //  indentation sensitive lexing: a line that starts a statement is
+    //  preceded by a "newline" token, then by an "indent" when it is further
+    //  in than the block it is in, or a "dedent" for every block it leaves;
+    //  these are empty tokens made by the factories of those names from
+    //  their names. Lines
+    //  inside brackets continue the statement. The end of input closes the
+    //  statement and all the blocks
+    pub fn set_layout(&self, layout: bool) {
+        self.st.borrow_mut().layout = if layout { Some(Layout::new()) } else { None };
+    }
+
+    //  the first dedent that matched no block, the tokens go on as if it
+    //  went back to the block it is in; the parser also fails with it
+    pub fn layout_error(&self) -> Option<String> {
+        self.layout_error.borrow().clone()
+    }
+
+    //  expressions inside strings between 'open' and 'close', e.g. "${" and "}":
+    //  "a ${x} b ${y} c" is lexed as the fragments string_head "a ${,
+    //  string_middle } b ${ and string_tail } c" around the tokens of x and y.
+    //  The expression ends at the first 'close' that does not close a
+    //  bracket opened in it, the bracket being the last char of 'open':
+    //  "${ {a} }" holds "{a}"
+    pub fn set_interpolation(&self, open: &str, close: &str) {
+        *self.interpolation.borrow_mut() = Some((String::from(open), String::from(close)));
+    }
+
+    //  the length of the delimiter at 'j', 'open' or 'close'
+    fn at_hole(&self, input: &str, j: usize, open: bool) -> Option<usize> {
+        match *self.interpolation.borrow() {
+            Some((ref o, ref c)) => {
+                let delimiter = if open { o } else { c };
+                if input[j..].starts_with(delimiter.as_str()) { Some(delimiter.len()) } else { None }
+            }
+            None => None,
+        }
+    }
+
+    //  'f' is asked first for every token while 'mode' is on top of the
+    //  mode stack, whitespace included; in streaming mode it sees at
+    //  least CHUNK bytes of input when there are that many left
+    pub fn register_mode(&self, mode: &'static str, f: FnScan) {
+        self.scanners.borrow_mut().insert(mode, f);
+    }
+
+    fn mode_token(&self, st: &TokenizerStatus, input: &str) -> Option<(usize, &'static str)> {
+        let mode = st.modes.last()?;
+        let scanners = self.scanners.borrow();
+        let (len, kind) = scanners.get(mode)?(&input[st.j..])?;
+        if len == 0 { None } else { Some((len, kind)) }
+    }
+
+    //  the current token and the peeked ones are lexed again with the
+    //  mode stack changed by 'change'
+    fn change_modes<F: Fn(&mut Vec<&'static str>)>(&self, change: F) {
+        let mut snapshot = self.snapshot();
+        change(&mut snapshot.state.modes);
+        if !self.rewind_to(&snapshot) {
+            // only the tokens after the ones already made
+            change(&mut self.st.borrow_mut().modes);
+        }
+    }
+
+    //  after the last token made, peeked ones included
+    pub fn state(&self) -> LexState {
+        LexState(self.st.borrow().clone())
+    }
+
+    //  lexing goes on from 'state', the tokens already made are kept
+    pub fn restore(&self, state: LexState) {
+        *self.st.borrow_mut() = state.0;
+    }
+
+    //  the next token's lexeme and the state after it, None at the end of input
+    fn next_lexeme(&self) -> Option<(Lexeme, LexState)> {
+        let n = self.made.get();
+        self.scan();
+        if self.made.get() == n {
+            return None;
+        }
+        Some((self.last.borrow().clone(), self.state()))
+    }
+
+    //  every token of the input with the state after it
+    pub fn lex(&self) -> Vec<(Lexeme, LexState)> {
+        let mut lexed = vec![];
+        while let Some(l) = self.next_lexeme() {
+            lexed.push(l);
+        }
+        lexed
+    }
+
+    //  the tokens of this tokenizer's input, which is 'edit' applied to the
+    //  text 'old' was lexed from: lexing restarts after the last token the
+    //  edit cannot change (one char of lookahead and trivia included) and
+    //  stops at the first new token equal to an old one past the edit
+    pub fn relex(&self, old: &[(Lexeme, LexState)], edit: &Edit) -> Vec<(Lexeme, LexState)> {
+        let delta = edit.delta();
+        let mut keep = old.iter()
+            .take_while(|(l, _)| l.span.end + l.trailing.len() < edit.range.start)
+            .count();
+        let restore = |keep: usize| self.restore(match keep {
+            0 => LexState(TokenizerStatus::start()),
+            k => old[k - 1].1.clone(),
+        });
+        restore(keep);
+        let mut first = self.next_lexeme();
+        // only trivia left: the trailing trivia of the token before runs to the end of input
+        if first.is_none() && keep > 0 {
+            keep -= 1;
+            restore(keep);
+            first = self.next_lexeme();
+        }
+        let mut lexed = old[..keep].to_vec();
+        let mut next = keep;
+        for (lexeme, state) in first.into_iter().chain(iter::from_fn(|| self.next_lexeme())) {
+            while next < old.len()
+                && (old[next].0.span.start < edit.range.end || shift(old[next].0.span.start, delta) < lexeme.span.start) {
+                next += 1;
+            }
+            // the flags for the trivia before the token say nothing of what follows it
+            let same = next < old.len()
+                && Lexeme { newline_before: lexeme.newline_before, space_before: lexeme.space_before, ..old[next].0.shifted(delta) } == lexeme
+                && old[next].1.shifted(delta) == state;
+            lexed.push((lexeme, state));
+            if same {
+                lexed.extend(old[next + 1..].iter().map(|(l, st)| (l.shifted(delta), st.shifted(delta))));
+                break;
+            }
+        }
+        lexed
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        let tokens = self.tokens.borrow();
+        let ahead = self.ahead.get();
+        let current = tokens.len() > ahead;
+        let (base, state) = match self.before.borrow().front() {
+            Some(before) => before.clone(),
+            None => (self.base.get(), self.st.borrow().clone()),
+        };
+        let made = self.made.get() - ahead - if current { 1 } else { 0 };
+        Snapshot { base, state, made, current, exhausted: self.exhausted.get() }
+    }
+
+    fn rewind_to(&self, snapshot: &Snapshot) -> bool {
+        // a streaming tokenizer may have dropped the input since
+        if snapshot.base + snapshot.state.i.min(snapshot.state.trivia) < self.base.get() {
+            return false;
+        }
+        let delta = snapshot.base as isize - self.base.get() as isize;
+        *self.st.borrow_mut() = LexState(snapshot.state.clone()).shifted(delta).0;
+        {
+            let mut tokens = self.tokens.borrow_mut();
+            let keep = tokens.len().saturating_sub(self.made.get() - snapshot.made);
+            tokens.truncate(keep);
+        }
+        self.made.set(snapshot.made);
+        self.ahead.set(0);
+        self.before.borrow_mut().clear();
+        if snapshot.current {
+            self.scan();
+        }
+        self.exhausted.set(snapshot.exhausted);
+        true
+    }
+
+    fn cancelled(&self) -> bool {
+        self.cancel.borrow().as_ref().is_some_and(|c| c.is_cancelled())
+    }
+}
+
+impl<S: Symbol> Tokenizer<S> for StringTokenizer<S> {
+
+    fn current(&self) -> Option<PrattBox<S>> {
+        self.peek(0)
+    }
+
+    fn peek(&self, n: usize) -> Option<PrattBox<S>> {
+        while self.ahead.get() < n {
+            let made = self.made.get();
+            self.ahead.set(self.ahead.get() + 1);
+            self.scan();
+            if self.made.get() == made {
+                self.ahead.set(self.ahead.get() - 1);
+                return None;
+            }
+        }
+        let tokens = self.tokens.borrow();
+        let i = tokens.len().checked_sub(1 + self.ahead.get() - n)?;
+        tokens.get(i).cloned()
+    }
+
+    fn set_cancel_handle(&self, cancel: CancelHandle) {
+        *self.cancel.borrow_mut() = Some(cancel);
+    }
+
+    fn exhausted(&self) -> bool {
+        self.exhausted.get()
+    }
+
+    fn checkpoint(&self) -> Option<Box<dyn Any>> {
+        Some(Box::new(self.snapshot()))
+    }
+
+    fn rewind(&self, checkpoint: &dyn Any) -> bool {
+        match checkpoint.downcast_ref::<Snapshot>() {
+            Some(snapshot) => self.rewind_to(snapshot),
+            None => false,
+        }
+    }
+
+    fn push_mode(&self, mode: &'static str) {
+        self.change_modes(|modes| modes.push(mode));
+    }
+
+    fn pop_mode(&self) {
+        self.change_modes(|modes| { modes.pop(); });
+    }
+
+    fn take_error(&self) -> Option<String> {
+        self.pending.borrow_mut().take()
+    }
+
+    //  made by the "missing" factory, or the "literal" one, from no text
+    fn missing(&self) -> Option<PrattBox<S>> {
+        let token = self.new_token("missing", "")?;
+        let end = self.base.get() + self.input.borrow().len();
+        if let Some(l) = token.borrow_mut().lexeme() {
+            *l = Lexeme {
+                kind: String::from("missing"),
+                span: end..end,
+                ..Lexeme::default()
+            };
+        }
+        Some(token)
+    }
+
+    //  empty, where the right operand starts
+    fn juxtaposition(&self) -> Option<PrattBox<S>> {
+        let at = self.current().and_then(|t| t.borrow_mut().lexeme().map(|l| l.span.start)).unwrap_or(0);
+        let juxtaposition = self.juxtaposition.borrow();
+        let (kind, ref f) = *juxtaposition.as_ref()?;
+        let token = f(kind);
+        if let Some(l) = token.borrow_mut().lexeme() {
+            *l = Lexeme {
+                kind: String::from(kind),
+                span: at..at,
+                ..Lexeme::default()
+            };
+        }
+        Some(token)
+    }
+
+
+    fn advance(&self) {
+        if self.ahead.get() > 0 {
+            self.ahead.set(self.ahead.get() - 1);
+            self.exhausted.set(false);
+            let mut before = self.before.borrow_mut();
+            if before.len() > self.ahead.get() + 1 {
+                before.pop_front();
+            }
+        } else {
+            self.scan();
+        }
+    }
+}
+
+impl<S: Symbol> StringTokenizer<S> {
+    //  lexes the next token onto 'tokens'
+    fn scan(&self) {
+        let mut st = self.st.borrow_mut();
+        let made = self.made.get();
+        let before = (self.base.get(), st.clone());
+        loop {
+            self.fill(st.j + self.lookahead());
+            if !st.modes.is_empty() {
+                self.fill(st.j + CHUNK);
+            }
+            if st.status == TokenStatus::Init && self.at_comment(&self.input.borrow(), st.j) {
+                // the whole comment line
+                while !self.input.borrow()[st.j..].contains('\n') && self.more() {}
+            }
+            let input = self.input.borrow();
+            if let Some(kind) = st.layout.as_mut().and_then(|l| l.queue.pop_front()) {
+                // empty in the source, named in the code
+                let span = st.j..st.j;
+                self.emit(&mut st, &input, kind, kind, span);
+                break;
+            }
+            if st.j >= input.len() && st.i < st.j
+                && (st.status == TokenStatus::InToken || st.status == TokenStatus::InNum) {
+                // the last token runs to the end of input
+                let span = st.i..st.j;
+                let s = &input[span.clone()];
+                let kind = if st.status == TokenStatus::InNum { String::from("num") } else { self.kind_of(s, "literal") };
+                self.emit(&mut st, &input, &kind, s, span);
+                st.i = st.j;
+                st.status = TokenStatus::Init;
+            }
+            if st.j >= input.len() && self.made.get() == made {
+                if let Some(ref mut layout) = st.layout {
+                    if layout.started && !layout.closed {
+                        layout.close();
+                        continue;
+                    }
+                }
+            }
+            if st.j >= input.len() || st.i >= input.len() || self.cancelled() {
+                break;
+            }
+            if st.status == TokenStatus::Init {
+                if let Some((len, kind)) = self.mode_token(&st, &input) {
+                    // EndToken steps past the last char
+                    let span = st.j..st.j + len;
+                    st.i = st.j;
+                    self.emit(&mut st, &input, kind, &input[span.clone()], span);
+                    st.j += len - 1;
+                    st.status = TokenStatus::EndToken;
+                    continue;
+                }
+            }
+            match st.status { 
+                TokenStatus::Init if self.at_comment(&input, st.j) => {
+                    st.j = self.trivia_end(&input, st.j, true);
+                    st.i = st.j;
+                    let line = input[..st.j].ends_with('\n');
+                    st.line |= line;
+                    st.space = true;
+                    if let Some(ref mut layout) = st.layout {
+                        if line {
+                            layout.space("\n");
+                        }
+                    }
+                }
+                TokenStatus::Init if st.layout.as_ref().is_some_and(|l| l.newline)
+                    && !matches!(char_at(&input, st.j), " " | "\t" | "\n") => {
+                    // the first token of a line
+                    if !st.layout.as_mut().unwrap().line() {
+                        let column = st.layout.as_ref().unwrap().column;
+                        let msg = format!("dedent to column {} at {} matches no block", column, self.base.get() + st.j);
+                        if self.layout_error.borrow().is_none() {
+                            *self.layout_error.borrow_mut() = Some(msg.clone());
+                        }
+                        self.fail(msg);
+                    }
+                }
+                TokenStatus::Init if st.holes.last() == Some(&0) && self.at_hole(&input, st.j, false).is_some() => {
+                    // the string goes on after the interpolation
+                    st.i = st.j;
+                    st.j += self.at_hole(&input, st.j, false).unwrap();
+                    st.holes.pop();
+                    st.status = TokenStatus::InString;
+                }
+                TokenStatus::Init => {
+                    let c = char_at(&input, st.j);
+                    if !st.holes.is_empty() {
+                        let close = self.at_hole(&input, st.j, false).is_some();
+                        let depth = st.holes.last_mut().unwrap();
+                        if close {
+                            *depth -= 1;
+                        } else if self.interpolation.borrow().as_ref().is_some_and(|(open, _)| open.ends_with(c)) {
+                            *depth += 1;
+                        }
+                    }
+                    match c {
+                        " " | "\t" | "\n"  => {
+                            st.line |= c == "\n";
+                            st.space = true;
+                            if let Some(ref mut layout) = st.layout {
+                                layout.space(c);
+                            }
+                            st.j += 1;
+                            st.i = st.j;
+                        }
+                        "'" => {
+                            st.j += 1;
+                            st.i = st.j;
+                            st.status = TokenStatus::InQuote;
+                        }
+                        "\"" => {
+                            st.j += 1;
+                            st.status = TokenStatus::InString;
+                        }
+                        "-" => {
+                            match input.get(st.j+1..st.j+2).unwrap_or("") { 
+                                "." | "0" | "1" | "2" | "3" 
+                                    | "4" | "5" | "6" | "7" 
+                                    | "8" | "9" => {
+                                    st.j += 1;
+                                    st.status = TokenStatus::InNum;
+                                }
+                                _ => {
+                                    // EndToken steps past the '-'
+                                    let s = &input[st.i..st.j+1];
+                                    let kind = self.kind_of(s, "literal");
+                                    let span = st.i..st.j+1;
+                                    self.emit(&mut st, &input, &kind, s, span);
+                                    st.status = TokenStatus::EndToken;
+                                }
+                            }
+                        }
+                        "." => {
+                            // a terminator when followed by whitespace or the end of input,
+                            // EndToken steps past it
+                            match char_at(&input, st.j+1) {
+                                "" | " " | "\t" | "\n" => {
+                                    let span = st.i..st.j+1;
+                                    self.emit(&mut st, &input, "end", ".", span);
+                                    st.status = TokenStatus::EndToken;
+                                }
+                                _ => {
+                                    st.j += 1;
+                                    st.status = TokenStatus::InNum;
+                                }
+                            }
+                        }
+                        "0" | "1" | "2" | "3" 
+                            | "4" | "5" | "6" | "7" 
+                            | "8" | "9" => {
+                            st.j += 1;
+                            st.status = TokenStatus::InNum;
+                        }
+                        _ => {
+                            if self.map.borrow().contains_key(c) {
+                                // EndToken steps past the last byte of the single
+                                // char token, so the next char is not swallowed
+                                let span = st.j..st.j+c.len();
+                                self.emit(&mut st, &input, c, c, span);
+                                st.j += c.len() - 1;
+                                st.status = TokenStatus::EndToken;
+                            } else {
+                                st.j += c.len();
+                                st.status = TokenStatus::InToken;
+                            }
+                        }
+                    }
+                }
+    
+                TokenStatus::InNum => {
+                    match char_at(&input, st.j) {
+                        "." | "0" | "1" | "2" | "3" 
+                            | "4" | "5" | "6" | "7" 
+                            | "8" | "9" => { st.j += 1; }
+                        // anything else ends the number: "2x" is 2 then x
+                        _ => {
+                            let span = st.i..st.j;
+                            self.emit(&mut st, &input, "num", &input[span.clone()], span);
+                            st.j -= 1;
+                            st.status = TokenStatus::EndToken;
+                        }
+                    }
+                }
+    
+    
+                TokenStatus::InToken => {
+                    // an interpolation's close ends the token too, as does a
+                    // single char token: "a-b" is a - b
+                    let close = !st.holes.is_empty() && self.at_hole(&input, st.j, false).is_some();
+                    let c = char_at(&input, st.j);
+                    match c {
+                        _ if close || self.map.borrow().contains_key(c) => {
+                            let s =&input[st.i..st.j];
+                            let kind = self.kind_of(s, "literal");
+                            let span = st.i..st.j;
+                            self.emit(&mut st, &input, &kind, s, span);
+                            st.j -= 1;
+                            st.status = TokenStatus::EndToken;
+                        }
+                        " " | "\t" | "\n" | "'" | "(" | ")" | "\"" => {
+                            let s =&input[st.i..st.j];
+                            let kind = self.kind_of(s, "literal");
+                            let span = st.i..st.j;
+                            self.emit(&mut st, &input, &kind, s, span);
+                            st.j -= 1;
+                            st.status = TokenStatus::EndToken;
+                        }
+                        _ => { st.j += c.len(); }
+                    }
+                }
+    
+                TokenStatus::InString => {
+                    // a fragment after an interpolation starts with its close
+                    let head = input[st.i..].starts_with('"');
+                    if let Some(len) = self.at_hole(&input, st.j, true) {
+                        // EndToken steps past the last char of 'open'
+                        let span = st.i..st.j+len;
+                        let kind = if head { "string_head" } else { "string_middle" };
+                        self.emit(&mut st, &input, kind, &input[span.clone()], span);
+                        st.j += len - 1;
+                        st.holes.push(0);
+                        st.status = TokenStatus::EndToken;
+                        continue;
+                    }
+                    let c = char_at(&input, st.j);
+                    match c {
+                        "\"" if head => {
+                            // the code keeps the opening quote, the span both
+                            let s = &input[st.i..st.j];    
+                            let span = st.i..st.j+1;
+                            self.emit(&mut st, &input, "string", s, span);
+                            st.status = TokenStatus::EndToken;
+                        }
+                        "\"" => {
+                            let span = st.i..st.j+1;
+                            self.emit(&mut st, &input, "string_tail", &input[span.clone()], span);
+                            st.status = TokenStatus::EndToken;
+                        }
+                        _ => { st.j += c.len(); }
+                    }
+                }
+    
+                TokenStatus::InQuote => {
+                    let mut ret = false;
+                    let c = char_at(&input, st.j);
+                    match c {
+                        " " | "\t" | "\n"
+                            if  st.inquotepar == 0 => {
+                                ret = true;
+                            }
+                        "(" => {
+                            st.inquotepar += 1; 
+                            st.j += 1; 
+                        }
+                        ")" => {
+                            if  st.inquotepar == 0 {
+                                ret = true;
+                            } else {
+                                st.inquotepar -= 1; 
+                                st.j += 1; 
+                            }
+                        }
+                        _ => { st.j += c.len(); }
+                    }
+                    if ret {
+                        
+                        let s = &input[st.i..st.j];
+                        let span = st.i-1..st.j;
+                        self.emit(&mut st, &input, "string", s, span);
+                        st.j -= 1;
+                        st.status = TokenStatus::EndToken;
+                    }
+                }
+                TokenStatus::EndToken => {
+                    //v.push(token.take().unwrap());    
+                    st.j += 1;
+                    st.i = st.j;
+                    st.status = TokenStatus::Init;
+                    break;
+                }
+            }
+        }
+        self.exhausted.set(self.made.get() == made && !self.cancelled());
+        if self.made.get() != made {
+            let mut befores = self.before.borrow_mut();
+            befores.push_back(before);
+            if befores.len() > self.ahead.get() + 1 {
+                befores.pop_front();
+            }
+            if self.lossless.get() {
+                self.trail(&mut st);
+            }
+            if self.source.borrow().is_some() {
+                self.compact(&mut st);
+            }
+        }
+    }
+}
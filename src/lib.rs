@@ -12,6 +12,14 @@ use gc3c::{Gc, Mark};
 #[cfg(feature="gc3c")]
 pub type PrattBox<T> = Gc<T>;
 
+use std::any::Any;
+use std::cell::RefCell;
+use std::error::Error;
+use std::fmt;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
 #[cfg(feature="gc3c")]
 #[macro_export]
 macro_rules! prattbox {
@@ -23,14 +31,45 @@ macro_rules! prattbox {
 #[cfg(feature="gc3c")]
 pub trait Symbol: Mark  {
     fn token(&mut self) -> &mut dyn Token<Self>;
-    fn nud(&mut self, this: PrattBox<Self>, pratt: &Pratt<Self>) -> PrattBox<Self> where Self: Sized {
-        self.token().nud(this, pratt)
+    //  by default the token's nud and led, over a Pratt<Self> that calls
+    //  the tokenizer dynamically; symbols that override them get the
+    //  parser with its tokenizer type, e.g. Pratt<Self, StringTokenizer<Self>>,
+    //  and every parse() they make is dispatched statically
+    fn nud<T: ?Sized + AsPratt<Self>>(&mut self, this: PrattBox<Self>, pratt: &Pratt<Self, T>) -> PrattBox<Self> where Self: Sized {
+        self.token().nud(this, T::as_pratt(pratt))
     }
-    fn led(&mut self, this: PrattBox<Self>, pratt: &Pratt<Self>, left: PrattBox<Self>) -> PrattBox<Self> where Self: Sized {
-        self.token().led(this, pratt, left)
+    fn led<T: ?Sized + AsPratt<Self>>(&mut self, this: PrattBox<Self>, pratt: &Pratt<Self, T>, left: PrattBox<Self>) -> PrattBox<Self> where Self: Sized {
+        self.token().led(this, T::as_pratt(pratt), left)
     }
     fn lbp(&mut self) -> u8 where Self: Sized {
-        self.token().lbp()
+        let (before, after) = self.lexeme().map_or((true, true), |l| (l.space_before, l.space_after));
+        self.token().spaced_lbp(before, after)
+    }
+    // symbols that keep their source get it filled in by the lexer
+    fn lexeme(&mut self) -> Option<&mut lexer::Lexeme> {
+        None
+    }
+    // the token that ends a top-level expression, by default the "end" kind
+    fn is_end(&mut self) -> bool {
+        self.lexeme().is_some_and(|l| l.kind == "end")
+    }
+    // a line break comes before the token
+    fn newline_before(&mut self) -> bool {
+        self.lexeme().is_some_and(|l| l.newline_before)
+    }
+    // whitespace around the token, e.g. "a -b" against "a - b"
+    fn space_before(&mut self) -> bool {
+        self.lexeme().is_some_and(|l| l.space_before)
+    }
+    fn space_after(&mut self) -> bool {
+        self.lexeme().is_some_and(|l| l.space_after)
+    }
+    // the led applies only when no line break comes before the token
+    fn restricted(&mut self) -> bool where Self: Sized {
+        self.token().restricted()
+    }
+    fn has_nud(&mut self) -> bool where Self: Sized {
+        self.token().has_nud()
     }
 }
 
@@ -38,14 +77,45 @@ pub trait Symbol: Mark  {
 #[cfg(not(feature="gc3c"))]
 pub trait Symbol  {
     fn token(&mut self) -> &mut dyn Token<Self>;
-    fn nud(&mut self, this: PrattBox<Self>, pratt: &Pratt<Self>) -> PrattBox<Self> where Self: Sized {
-        self.token().nud(this, pratt)
+    //  by default the token's nud and led, over a Pratt<Self> that calls
+    //  the tokenizer dynamically; symbols that override them get the
+    //  parser with its tokenizer type, e.g. Pratt<Self, StringTokenizer<Self>>,
+    //  and every parse() they make is dispatched statically
+    fn nud<T: ?Sized + AsPratt<Self>>(&mut self, this: PrattBox<Self>, pratt: &Pratt<Self, T>) -> PrattBox<Self> where Self: Sized {
+        self.token().nud(this, T::as_pratt(pratt))
     }
-    fn led(&mut self, this: PrattBox<Self>, pratt: &Pratt<Self>, left: PrattBox<Self>) -> PrattBox<Self> where Self: Sized {
-        self.token().led(this, pratt, left)
+    fn led<T: ?Sized + AsPratt<Self>>(&mut self, this: PrattBox<Self>, pratt: &Pratt<Self, T>, left: PrattBox<Self>) -> PrattBox<Self> where Self: Sized {
+        self.token().led(this, T::as_pratt(pratt), left)
     }
     fn lbp(&mut self) -> u8 where Self: Sized {
-        self.token().lbp()
+        let (before, after) = self.lexeme().map_or((true, true), |l| (l.space_before, l.space_after));
+        self.token().spaced_lbp(before, after)
+    }
+    // symbols that keep their source get it filled in by the lexer
+    fn lexeme(&mut self) -> Option<&mut lexer::Lexeme> {
+        None
+    }
+    // the token that ends a top-level expression, by default the "end" kind
+    fn is_end(&mut self) -> bool {
+        self.lexeme().is_some_and(|l| l.kind == "end")
+    }
+    // a line break comes before the token
+    fn newline_before(&mut self) -> bool {
+        self.lexeme().is_some_and(|l| l.newline_before)
+    }
+    // whitespace around the token, e.g. "a -b" against "a - b"
+    fn space_before(&mut self) -> bool {
+        self.lexeme().is_some_and(|l| l.space_before)
+    }
+    fn space_after(&mut self) -> bool {
+        self.lexeme().is_some_and(|l| l.space_after)
+    }
+    // the led applies only when no line break comes before the token
+    fn restricted(&mut self) -> bool where Self: Sized {
+        self.token().restricted()
+    }
+    fn has_nud(&mut self) -> bool where Self: Sized {
+        self.token().has_nud()
     }
 }
 
@@ -58,56 +128,580 @@ pub trait Token<S: Symbol>  {
         unreachable!();
     }
     fn lbp(&self) -> u8 ;
+    // the lbp given the whitespace around the token
+    fn spaced_lbp(&self, _space_before: bool, _space_after: bool) -> u8 {
+        self.lbp()
+    }
+    fn restricted(&self) -> bool {
+        false
+    }
+    // the token can start an operand, for juxtaposition
+    fn has_nud(&self) -> bool {
+        false
+    }
 }
     
 
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    Cancelled,
+    Syntax(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::Cancelled => write!(f, "parse cancelled"),
+            ParseError::Syntax(ref msg) => write!(f, "syntax error: {}", msg),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+
+//  shared flag to abandon a parse from another thread;
+//  the parser polls it between tokens, so nud/led are never interrupted halfway
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle {
+    flag: Arc<AtomicBool>,
+}
+
+impl CancelHandle {
+    pub fn new() -> CancelHandle {
+        CancelHandle::default()
+    }
+    pub fn cancel(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+    //  parsers cancelled by the handle can parse again
+    pub fn reset(&self) {
+        self.flag.store(false, Ordering::SeqCst);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+}
+
+
 pub trait Tokenizer<S: Symbol> {
     fn advance(&self);
     fn current(& self) -> Option<PrattBox<S>>;
+    //  the token 'n' places after the current one without moving to it,
+    //  None past the end of input; tokenizers without lookahead see only current()
+    fn peek(&self, n: usize) -> Option<PrattBox<S>> {
+        if n == 0 { self.current() } else { None }
+    }
+    // tokenizers with long scanning loops can keep the handle and poll it
+    fn set_cancel_handle(&self, _cancel: CancelHandle) {}
+    // true once advance() found no token left to move to
+    fn exhausted(&self) -> bool {
+        false
+    }
+    //  a position to rewind() to, None for tokenizers that cannot go back
+    fn checkpoint(&self) -> Option<Box<dyn Any>> {
+        None
+    }
+    //  back to 'checkpoint', false when that is no longer possible
+    fn rewind(&self, _checkpoint: &dyn Any) -> bool {
+        false
+    }
+    //  lexer modes, switched by nud/led: the current token and the
+    //  peeked ones are lexed again in the new mode
+    fn push_mode(&self, _mode: &'static str) {}
+    fn pop_mode(&self) {}
+    //  true when the current token is not in the input, e.g. a terminator
+    //  put in at a line break
+    fn inserted(&self) -> bool {
+        false
+    }
+    //  an error found while lexing, e.g. a dedent that matches no block;
+    //  given once, the parser fails with it as a syntax error
+    fn take_error(&self) -> Option<String> {
+        None
+    }
+    //  an empty token in place of the operand missing at the end of input,
+    //  returned by the failed parse; None when the tokenizer cannot make one
+    fn missing(&self) -> Option<PrattBox<S>> {
+        None
+    }
+    //  the operator between adjacent operands, "f x" and "2 x", made like
+    //  a token but with no text; None when operands do not juxtapose
+    fn juxtaposition(&self) -> Option<PrattBox<S>> {
+        None
+    }
+}
+
+impl<S: Symbol, T: ?Sized + Tokenizer<S>> Tokenizer<S> for Box<T> {
+    fn advance(&self) {
+        (**self).advance()
+    }
+    fn current(& self) -> Option<PrattBox<S>> {
+        (**self).current()
+    }
+    fn peek(&self, n: usize) -> Option<PrattBox<S>> {
+        (**self).peek(n)
+    }
+    fn set_cancel_handle(&self, cancel: CancelHandle) {
+        (**self).set_cancel_handle(cancel)
+    }
+    fn exhausted(&self) -> bool {
+        (**self).exhausted()
+    }
+    fn checkpoint(&self) -> Option<Box<dyn Any>> {
+        (**self).checkpoint()
+    }
+    fn rewind(&self, checkpoint: &dyn Any) -> bool {
+        (**self).rewind(checkpoint)
+    }
+    fn push_mode(&self, mode: &'static str) {
+        (**self).push_mode(mode)
+    }
+    fn pop_mode(&self) {
+        (**self).pop_mode()
+    }
+    fn inserted(&self) -> bool {
+        (**self).inserted()
+    }
+    fn take_error(&self) -> Option<String> {
+        (**self).take_error()
+    }
+    fn missing(&self) -> Option<PrattBox<S>> {
+        (**self).missing()
+    }
+    fn juxtaposition(&self) -> Option<PrattBox<S>> {
+        (**self).juxtaposition()
+    }
 }
 
-pub struct Pratt<S: Symbol> {
-    tokenizer: Box<dyn Tokenizer<S>>,
+//  where a parse was, see Pratt::checkpoint()
+pub struct Checkpoint<S: Symbol> {
+    tokenizer: Box<dyn Any>,
+    previous: Option<PrattBox<S>>,
 }
 
-impl<S: Symbol> Pratt<S> {
-    pub fn new(tokenizer: Box<dyn Tokenizer<S>>) -> Pratt<S> {
-        Pratt { tokenizer: tokenizer }
+//  the tokenizer is a type parameter: parse() calls it statically and it
+//  comes back with into_tokenizer(). Symbol::nud/led get the parser as a
+//  Pratt<S, T> too; the Token ones, behind 'dyn Token<S>', get it as a
+//  Pratt<S>, over 'dyn Tokenizer<S>'. That Pratt<S> is unsized: an owned
+//  parser is a Pratt<S, T> or a BoxedPratt<S>
+pub struct Pratt<S: Symbol, T: ?Sized + Tokenizer<S> = dyn Tokenizer<S>> {
+    cancel: CancelHandle,
+    error: RefCell<Option<ParseError>>,
+    tracer: Option<Rc<dyn Tracer<S>>>,
+    // the token advanced past last
+    previous: RefCell<Option<PrattBox<S>>>,
+    // nud/led add children to their nodes, see set_build_tree()
+    build_tree: bool,
+    // last, so that a &Pratt<S, T> coerces to a &Pratt<S>
+    tokenizer: T,
+}
+
+//  the parser over a boxed tokenizer, the owned Pratt<S> of before
+pub type BoxedPratt<S> = Pratt<S, Box<dyn Tokenizer<S>>>;
+
+//  tokenizers a parser can hand to nud and led as a Pratt<S>
+pub trait AsPratt<S: Symbol>: Tokenizer<S> {
+    fn as_pratt(pratt: &Pratt<S, Self>) -> &Pratt<S>;
+}
+
+impl<S: Symbol> AsPratt<S> for dyn Tokenizer<S> {
+    fn as_pratt(pratt: &Pratt<S>) -> &Pratt<S> {
+        pratt
+    }
+}
+
+impl<S: Symbol, T: Tokenizer<S> + 'static> AsPratt<S> for T {
+    fn as_pratt(pratt: &Pratt<S, T>) -> &Pratt<S> {
+        pratt
+    }
+}
+
+impl<S: Symbol, T: Tokenizer<S>> Pratt<S, T> {
+    pub fn new(tokenizer: T) -> Pratt<S, T> {
+        let cancel = CancelHandle::new();
+        tokenizer.set_cancel_handle(cancel.clone());
+        Pratt { cancel, error: RefCell::new(None), tracer: None, previous: RefCell::new(None), build_tree: true, tokenizer }
+    }
+
+    //  the tokenizer back, e.g. to keep lexing after the parse
+    pub fn into_tokenizer(self) -> T {
+        self.tokenizer
+    }
+}
+
+impl<S: Symbol, T: ?Sized + AsPratt<S>> Pratt<S, T> {
+    pub fn set_tracer(&mut self, tracer: Rc<dyn Tracer<S>>) {
+        self.tracer = Some(tracer);
+    }
+
+    //  off when only a tracer's events are wanted, see events::parse():
+    //  nud/led return their nodes but leave out the children
+    pub fn set_build_tree(&mut self, build: bool) {
+        self.build_tree = build;
+    }
+
+    pub fn build_tree(&self) -> bool {
+        self.build_tree
+    }
+
+    fn trace(&self, event: TraceEvent<S>) {
+        if let Some(ref tracer) = self.tracer {
+            tracer.trace(&event);
+        }
+    }
+
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    pub fn error(&self) -> Option<ParseError> {
+        self.forget_reset();
+        self.error.borrow().clone()
+    }
+
+    //  a cancellation is over once the handle is reset
+    fn forget_reset(&self) {
+        let mut error = self.error.borrow_mut();
+        if *error == Some(ParseError::Cancelled) && !self.cancel.is_cancelled() {
+            *error = None;
+        }
+    }
+
+    //  for nud/led: record an error and let the parse unwind like a cancellation
+    pub fn fail(&self, err: ParseError) {
+        let mut error = self.error.borrow_mut();
+        if error.is_none() {
+            *error = Some(err);
+        }
+    }
+
+    //  true once the parse has to unwind; the first error wins
+    fn failed(&self) -> bool {
+        self.forget_reset();
+        if self.error.borrow().is_some() {
+            return true;
+        }
+        if self.cancel.is_cancelled() {
+            *self.error.borrow_mut() = Some(ParseError::Cancelled);
+            return true;
+        }
+        false
+    }
+
+    //  forgets the error of the last parse, not a cancellation
+    fn clear_error(&self) -> Option<ParseError> {
+        self.error.borrow_mut().take()
+    }
+
+    fn check(&self) -> Result<(), ParseError> {
+        if self.failed() {
+            Err(self.error().unwrap())
+        } else {
+            Ok(())
+        }
     }
 
     pub fn advance(&self) {
-        self.tokenizer.advance()
+        if self.failed() {
+            return;
+        }
+        *self.previous.borrow_mut() = self.tokenizer.current();
+        self.tokenizer.advance();
+        self.lex_error();
+        self.trace_current(false);
+    }
+    fn lex_error(&self) {
+        if let Some(msg) = self.tokenizer.take_error() {
+            self.fail(ParseError::Syntax(msg));
+        }
     }
+    //  None at the end of input, where the tokenizer stays on its last
+    //  token: a nud or led may still be running on it
     pub fn current(& self) -> Option<PrattBox<S>> {
-        self.tokenizer.current()
+        if self.tokenizer.exhausted() {
+            None
+        } else {
+            self.tokenizer.current()
+        }
+    }
+    //  e.g. in operand position, where '/' starts a regex and not a division;
+    //  tracers see the current token again as a Relex
+    pub fn push_mode(&self, mode: &'static str) {
+        self.tokenizer.push_mode(mode);
+        self.trace_current(true);
+    }
+    pub fn pop_mode(&self) {
+        self.tokenizer.pop_mode();
+        self.trace_current(true);
+    }
+    fn trace_current(&self, relex: bool) {
+        if self.tracer.is_some() {
+            if let Some(t) = self.current() {
+                if relex {
+                    self.trace(TraceEvent::Relex(&t));
+                } else if self.tokenizer.inserted() {
+                    self.trace(TraceEvent::Inserted(&t));
+                } else {
+                    self.trace(TraceEvent::Advance(&t));
+                }
+            }
+        }
+    }
+
+    //  a line break before the current token, e.g. for a nud whose
+    //  operand has to be on the same line
+    pub fn newline_before(&self) -> bool {
+        self.current().is_some_and(|t| t.borrow_mut().newline_before())
+    }
+
+    //  for nud/led that have to look further than the current token
+    pub fn peek(&self, n: usize) -> Option<PrattBox<S>> {
+        self.tokenizer.peek(n)
     }
 
     fn nud(&self, this: PrattBox<S>) -> PrattBox<S> {
-        this.borrow_mut().nud(this.clone(), self)
+        self.trace(TraceEvent::Nud(&this));
+        let node = this.borrow_mut().nud(this.clone(), self);
+        self.trace(TraceEvent::Return(&node));
+        node
     }
     fn led(&self, this: PrattBox<S>, left: PrattBox<S>) -> PrattBox<S> {
-        this.borrow_mut().led(this.clone(), self, left)
+        self.trace(TraceEvent::Led(&this, &left));
+        let node = this.borrow_mut().led(this.clone(), self, left);
+        self.trace(TraceEvent::Return(&node));
+        node
     }
 
+    //  a restricted led does not take a left operand on the line before
+    fn binds(&self, rbp: u8, lookahead: &PrattBox<S>, left: &PrattBox<S>) -> bool {
+        let (lbp, restricted) = {
+            let mut symbol = lookahead.borrow_mut();
+            (symbol.lbp(), symbol.restricted() && symbol.newline_before())
+        };
+        let binds = rbp < lbp && !restricted;
+        self.trace(TraceEvent::Compare { rbp, lbp, lookahead, left, binds });
+        binds
+    }
+
+    //  "f x": a lookahead with lbp 0 that starts an operand binds like the
+    //  tokenizer's juxtaposition operator, which is returned to run the led
+    fn juxtaposes(&self, rbp: u8, lookahead: &PrattBox<S>, left: &PrattBox<S>) -> Option<PrattBox<S>> {
+        {
+            let mut symbol = lookahead.borrow_mut();
+            if symbol.lbp() > 0 || !symbol.has_nud() {
+                return None;
+            }
+        }
+        let op = self.tokenizer.juxtaposition()?;
+        let lbp = op.borrow_mut().lbp();
+        let binds = rbp < lbp;
+        self.trace(TraceEvent::Compare { rbp, lbp, lookahead, left, binds });
+        if binds { Some(op) } else { None }
+    }
+
+    //  the token the nud starts from; at the end of input the parse fails
+    //  and returns the tokenizer's stand-in for the missing operand
+    fn operand(&self) -> PrattBox<S> {
+        if let Some(t) = self.current() {
+            return t;
+        }
+        self.fail(ParseError::Syntax(String::from("unexpected end of input")));
+        self.tokenizer.missing().or_else(|| self.tokenizer.current())
+            .expect("unexpected end of input, the tokenizer has no token in place of the operand")
+    }
+
+    //  on cancellation every level returns the node it holds,
+    //  so the partial tree stays well formed while the stack unwinds
     pub fn parse(&self, rbp: u8) -> PrattBox<S>  {
-        let mut t = self.current().unwrap();
+        let mut t = self.operand();
+        if self.failed() {
+            return t;
+        }
         self.advance();
+        if self.failed() {
+            return t;
+        }
         let mut left = self.nud(t);
-        let mut lookahead = self.current().unwrap(); 
-        while rbp < lookahead.borrow_mut().lbp() {
-            t = lookahead;
-            self.advance();
-            left = self.led(t, left);
-            lookahead = self.current().unwrap();
+        if self.failed() {
+            return left;
+        }
+        let mut lookahead = match self.current() {
+            Some(t) => t,
+            None => return left,
+        };
+        loop {
+            if self.binds(rbp, &lookahead, &left) {
+                t = lookahead;
+                self.advance();
+                if self.failed() {
+                    return left;
+                }
+                left = self.led(t, left);
+            } else if let Some(op) = self.juxtaposes(rbp, &lookahead, &left) {
+                left = self.led(op, left);
+            } else {
+                break;
+            }
+            if self.failed() {
+                return left;
+            }
+            lookahead = match self.current() {
+                Some(t) => t,
+                None => break,
+            };
         }
-        return left;
+        left
     }
 
+    //  after an error or a cancellation this is the partial tree, or the
+    //  tokenizer's stand-in when there was nothing to parse; see error()
     pub fn pparse(& self) -> PrattBox<S>  {
         self.advance();
         self.parse(0)
     }
+
+    pub fn try_pparse(& self) -> Result<PrattBox<S>, ParseError>  {
+        self.advance();
+        self.check()?;
+        let ast = self.parse(0);
+        self.check()?;
+        Ok(ast)
+    }
+
+    //  the tokenizer's position, for a speculative parse; None when the
+    //  tokenizer cannot go back
+    pub fn checkpoint(&self) -> Option<Checkpoint<S>> {
+        let tokenizer = self.tokenizer.checkpoint()?;
+        Some(Checkpoint { tokenizer, previous: self.previous.borrow().clone() })
+    }
+
+    //  back to 'checkpoint', forgetting a syntax error; the current token is
+    //  made again, nodes built since are dropped with their last reference.
+    //  Tracers are not told, they have seen the abandoned attempt too
+    pub fn rewind(&self, checkpoint: Checkpoint<S>) -> Result<(), ParseError> {
+        if !self.tokenizer.rewind(&*checkpoint.tokenizer) {
+            return Err(ParseError::Syntax(String::from("cannot rewind the tokenizer")));
+        }
+        *self.previous.borrow_mut() = checkpoint.previous;
+        if self.error().is_some_and(|e| e != ParseError::Cancelled) {
+            self.clear_error();
+        }
+        Ok(())
+    }
+
+    //  runs 'attempt' and rewinds if it fails with a syntax error, so the
+    //  caller can try another way; None then, or on a cancellation. Without
+    //  a checkpoint from the tokenizer the error stays
+    pub fn try_parse<R, F: FnOnce(&Self) -> R>(&self, attempt: F) -> Option<R> {
+        if self.failed() {
+            return None;
+        }
+        let checkpoint = self.checkpoint();
+        let result = attempt(self);
+        if !self.failed() {
+            return Some(result);
+        }
+        drop(result);
+        if let Some(checkpoint) = checkpoint {
+            if self.error() != Some(ParseError::Cancelled) {
+                let _ = self.rewind(checkpoint);
+            }
+        }
+        None
+    }
+
+    //  every top-level expression up to its end token, to the end of input
+    pub fn expressions(&self) -> Expressions<'_, S, T> {
+        Expressions { pratt: self, done: false, started: false }
+    }
+}
+
+//  after a syntax error the tokens up to the next end token are skipped,
+//  a cancellation ends the iteration
+pub struct Expressions<'a, S: Symbol + 'a, T: ?Sized + AsPratt<S> + 'a = dyn Tokenizer<S>> {
+    pratt: &'a Pratt<S, T>,
+    done: bool,
+    // the current token starts the next expression
+    started: bool,
+}
+
+impl<'a, S: Symbol, T: ?Sized + AsPratt<S>> Expressions<'a, S, T> {
+    fn at_end(&self) -> bool {
+        self.pratt.current().is_some_and(|t| t.borrow_mut().is_end())
+    }
+
+    fn expression(&self) -> Result<PrattBox<S>, ParseError> {
+        let ast = self.pratt.parse(0);
+        self.pratt.check()?;
+        if !self.at_end() && !self.pratt.tokenizer.exhausted() {
+            let found = self.pratt.current().and_then(|t| t.borrow_mut().lexeme().map(|l| l.text.clone()));
+            return Err(ParseError::Syntax(format!("expected end of expression, found {}", found.unwrap_or_default())));
+        }
+        Ok(ast)
+    }
+}
+
+impl<'a, S: Symbol, T: ?Sized + AsPratt<S>> Iterator for Expressions<'a, S, T> {
+    type Item = Result<PrattBox<S>, ParseError>;
+
+    fn next(&mut self) -> Option<Result<PrattBox<S>, ParseError>> {
+        if self.done {
+            return None;
+        }
+        // moves past the end token of the last expression
+        if !self.started {
+            self.pratt.advance();
+        }
+        self.started = false;
+        // a lex error goes with the expression it is in
+        let result = match self.pratt.check() {
+            Err(err) => Err(err),
+            Ok(()) if self.pratt.tokenizer.exhausted() => {
+                self.done = true;
+                return None;
+            }
+            Ok(()) => self.expression(),
+        };
+        match result {
+            Err(ParseError::Cancelled) => self.done = true,
+            Err(_) => {
+                self.pratt.clear_error();
+                // the error may come from the end token itself
+                let previous = self.pratt.previous.borrow().clone();
+                if !self.at_end() && previous.is_some_and(|t| t.borrow_mut().is_end()) {
+                    self.started = true;
+                }
+                while !self.started && !self.at_end() && !self.pratt.tokenizer.exhausted() {
+                    self.pratt.advance();
+                    if self.pratt.error().is_some_and(|e| e != ParseError::Cancelled) {
+                        self.pratt.clear_error();
+                    }
+                    if self.pratt.failed() {
+                        break;
+                    }
+                }
+            }
+            Ok(_) => {}
+        }
+        Some(result)
+    }
 }
 
 pub mod dyn;
+pub mod lexer;
+pub mod grammar;
+pub mod table;
+pub mod railroad;
+pub mod pretty;
+pub mod printer;
+pub mod trace;
+pub mod replay;
+pub mod events;
+pub mod cst;
+pub mod green;
+pub mod red;
+pub mod incremental;
+pub mod terminators;
+use trace::{TraceEvent, Tracer};
@@ -0,0 +1,116 @@
+use std::rc::Rc;
+
+//  Wadler style document algebra: a Doc is laid out for a line width,
+//  each Group is printed flat when it fits, otherwise its lines break
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Doc {
+    Nil,
+    Text(String),
+    // a space when flat
+    Line,
+    // nothing when flat
+    SoftLine,
+    Nest(usize, Rc<Doc>),
+    Group(Rc<Doc>),
+    Concat(Vec<Doc>),
+}
+
+pub fn text(s: &str) -> Doc {
+    Doc::Text(String::from(s))
+}
+
+pub fn nest(indent: usize, doc: Doc) -> Doc {
+    Doc::Nest(indent, Rc::new(doc))
+}
+
+pub fn group(doc: Doc) -> Doc {
+    Doc::Group(Rc::new(doc))
+}
+
+pub fn concat(docs: Vec<Doc>) -> Doc {
+    Doc::Concat(docs)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Flat,
+    Break,
+}
+
+type Cmd<'a> = (usize, Mode, &'a Doc);
+
+//  does 'next' fit in 'rem' columns, followed by the rest up to its first break
+fn fits(mut rem: isize, next: Cmd, rest: &[Cmd]) -> bool {
+    let mut stack = vec![next];
+    let mut pending = rest.len();
+    loop {
+        if rem < 0 {
+            return false;
+        }
+        let (i, mode, doc) = match stack.pop() {
+            Some(cmd) => cmd,
+            None if pending == 0 => return true,
+            None => {
+                pending -= 1;
+                rest[pending]
+            }
+        };
+        match *doc {
+            Doc::Nil => {}
+            Doc::Text(ref s) => rem -= s.chars().count() as isize,
+            Doc::Line | Doc::SoftLine if mode == Mode::Break => return true,
+            Doc::Line => rem -= 1,
+            Doc::SoftLine => {}
+            Doc::Nest(j, ref d) => stack.push((i + j, mode, d)),
+            Doc::Group(ref d) => stack.push((i, mode, d)),
+            Doc::Concat(ref ds) => {
+                for d in ds.iter().rev() {
+                    stack.push((i, mode, d));
+                }
+            }
+        }
+    }
+}
+
+impl Doc {
+    pub fn render(&self, width: usize) -> String {
+        let mut out = String::new();
+        let mut col = 0;
+        let mut stack: Vec<Cmd> = vec![(0, Mode::Break, self)];
+        while let Some((i, mode, doc)) = stack.pop() {
+            match *doc {
+                Doc::Nil => {}
+                Doc::Text(ref s) => {
+                    out.push_str(s);
+                    col += s.chars().count();
+                }
+                Doc::Line | Doc::SoftLine if mode == Mode::Break => {
+                    out.push('\n');
+                    out.push_str(&" ".repeat(i));
+                    col = i;
+                }
+                Doc::Line => {
+                    out.push(' ');
+                    col += 1;
+                }
+                Doc::SoftLine => {}
+                Doc::Nest(j, ref d) => stack.push((i + j, mode, d)),
+                Doc::Group(ref d) => {
+                    let rem = width.saturating_sub(col).min(isize::MAX as usize) as isize;
+                    if mode == Mode::Flat || fits(rem, (i, Mode::Flat, d), &stack) {
+                        stack.push((i, Mode::Flat, d));
+                    } else {
+                        stack.push((i, Mode::Break, d));
+                    }
+                }
+                Doc::Concat(ref ds) => {
+                    for d in ds.iter().rev() {
+                        stack.push((i, mode, d));
+                    }
+                }
+            }
+        }
+        out
+    }
+}
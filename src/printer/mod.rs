@@ -0,0 +1,243 @@
+use super::{PrattBox, Symbol};
+use super::grammar::{Grammar, Infix, Prefix};
+use super::dyn::DynamicSymbol;
+use super::pretty::{Doc, text, nest, group, concat};
+
+//  unparser: prints a tree back as source, with parentheses only where
+//  the binding powers would otherwise parse it differently;
+//  the layout goes through a pretty::Doc so the same tree can be formatted
+//  to a line width
+
+//  how a node is written, with the binding powers the parser used for it
+#[derive(Debug, Clone, PartialEq)]
+pub enum Shape {
+    Atom,
+    Prefix { rbp: u8 },
+    Infix { lbp: u8, rbp: u8 },
+    Postfix { lbp: u8 },
+    // first child is the callee, the others are the arguments
+    Call { lbp: u8, sep: String, close: String },
+    PrefixMixfix { parts: Vec<String>, rbp: u8 },
+    InfixMixfix { lbp: u8, parts: Vec<String>, rbp: u8 },
+    // adjacent operands, the text is not printed
+    Juxtaposition { lbp: u8, rbp: u8 },
+}
+
+pub struct Form<S: Symbol> {
+    pub text: String,
+    pub shape: Shape,
+    pub children: Vec<PrattBox<S>>,
+}
+
+//  tells the printer text, shape and children of a node
+pub trait Describe<S: Symbol> {
+    fn describe(&self, node: &mut S) -> Form<S>;
+    fn parens(&self) -> (String, String) {
+        (String::from("("), String::from(")"))
+    }
+}
+
+impl Describe<DynamicSymbol> for Grammar {
+    fn describe(&self, node: &mut DynamicSymbol) -> Form<DynamicSymbol> {
+        let token = &node.token;
+        let n = token.children.len();
+        let strings = |parts: &[&str]| parts.iter().map(|p| p.to_string()).collect();
+        if let Some(rule) = self.juxtaposition_rule() {
+            if let Some(Infix::Operator { rbp }) = rule.infix {
+                if rule.name == token.code && n == 2 {
+                    let shape = Shape::Juxtaposition { lbp: rule.lbp, rbp };
+                    return Form { text: token.code.clone(), shape, children: token.children.clone() };
+                }
+            }
+        }
+        let shape = self.rule(&token.code).and_then(|rule| {
+            let prefix = match rule.prefix {
+                Some(Prefix::Operator { rbp }) if n == 1 =>
+                    Some(Shape::Prefix { rbp }),
+                Some(Prefix::Mixfix { ref parts, rbp }) if n == parts.len() + 1 =>
+                    Some(Shape::PrefixMixfix { parts: strings(parts), rbp }),
+                _ => None,
+            };
+            let infix = match rule.infix {
+                Some(Infix::Operator { rbp }) if n == 2 =>
+                    Some(Shape::Infix { lbp: rule.lbp, rbp }),
+                Some(Infix::Postfix) if n == 1 =>
+                    Some(Shape::Postfix { lbp: rule.lbp }),
+                Some(Infix::Call { sep, close }) if n >= 1 =>
+                    Some(Shape::Call { lbp: rule.lbp, sep: sep.to_string(), close: close.to_string() }),
+                Some(Infix::Mixfix { ref parts, rbp }) if n == parts.len() + 2 =>
+                    Some(Shape::InfixMixfix { lbp: rule.lbp, parts: strings(parts), rbp }),
+                _ => None,
+            };
+            prefix.or(infix)
+        });
+        Form { text: token.code.clone(), shape: shape.unwrap_or(Shape::Atom), children: token.children.clone() }
+    }
+
+    fn parens(&self) -> (String, String) {
+        for rule in self.rules() {
+            if let Some(Prefix::Group { close }) = rule.prefix {
+                return (rule.name.to_string(), close.to_string());
+            }
+        }
+        (String::from("("), String::from(")"))
+    }
+}
+
+
+//  a node parsed by parse(rbp) ends before the next operator with lbp 'follow'
+struct Context {
+    rbp: u8,
+    follow: u8,
+}
+
+const FREE: Context = Context { rbp: 0, follow: 0 };
+
+fn needs_parens(shape: &Shape, ctx: &Context) -> bool {
+    // the led must have been taken by the loop of parse(ctx.rbp)
+    let taken = match *shape {
+        Shape::Infix { lbp, .. } | Shape::Postfix { lbp } | Shape::Call { lbp, .. }
+            | Shape::InfixMixfix { lbp, .. } | Shape::Juxtaposition { lbp, .. } => lbp > ctx.rbp,
+        _ => true,
+    };
+    // the trailing operand must stop before the following operator
+    let stops = match *shape {
+        Shape::Prefix { rbp } | Shape::Infix { rbp, .. } | Shape::Juxtaposition { rbp, .. }
+            | Shape::PrefixMixfix { rbp, .. } | Shape::InfixMixfix { rbp, .. } => rbp >= ctx.follow,
+        _ => true,
+    };
+    !(taken && stops)
+}
+
+const INDENT: usize = 4;
+
+fn node_doc<S: Symbol, D: Describe<S>>(d: &D, node: &PrattBox<S>, ctx: Context) -> Doc {
+    let form = d.describe(&mut *node.borrow_mut());
+    wrap(d, form, ctx)
+}
+
+fn wrap<S: Symbol, D: Describe<S>>(d: &D, form: Form<S>, ctx: Context) -> Doc {
+    if needs_parens(&form.shape, &ctx) {
+        parenthesized(d, form)
+    } else {
+        form_doc(d, form, ctx)
+    }
+}
+
+fn parenthesized<S: Symbol, D: Describe<S>>(d: &D, form: Form<S>) -> Doc {
+    let (open, close) = d.parens();
+    concat(vec![text(&open), nest(open.chars().count(), form_doc(d, form, FREE)), text(&close)])
+}
+
+//  a b c ... laid out as "a", then each part on its own line when broken
+fn hanging(first: Doc, rest: Vec<Doc>) -> Doc {
+    let mut tail = Vec::new();
+    for r in rest {
+        tail.push(Doc::Line);
+        tail.push(r);
+    }
+    group(nest(INDENT, concat(vec![first, concat(tail)])))
+}
+
+fn form_doc<S: Symbol, D: Describe<S>>(d: &D, form: Form<S>, ctx: Context) -> Doc {
+    let c = form.children.clone();
+    match form.shape {
+        Shape::Atom => text(&form.text),
+        Shape::Prefix { rbp } => {
+            concat(vec![text(&form.text), text(" "), node_doc(d, &c[0], Context { rbp, follow: ctx.follow })])
+        }
+        Shape::Infix { lbp, .. } => {
+            // a chain of operators of the same precedence breaks as a whole
+            let mut rest = Vec::new();
+            let mut form = form;
+            let mut ctx = ctx;
+            loop {
+                let rbp = match form.shape {
+                    Shape::Infix { rbp, .. } => rbp,
+                    _ => unreachable!(),
+                };
+                let right = node_doc(d, &form.children[1], Context { rbp, follow: ctx.follow });
+                rest.push(concat(vec![text(&form.text), text(" "), right]));
+                let left_ctx = Context { rbp: ctx.rbp, follow: lbp };
+                let left = d.describe(&mut *form.children[0].borrow_mut());
+                match left.shape {
+                    Shape::Infix { lbp: l, .. } if l == lbp && !needs_parens(&left.shape, &left_ctx) => {
+                        form = left;
+                        ctx = left_ctx;
+                    }
+                    _ => {
+                        rest.reverse();
+                        return hanging(wrap(d, left, left_ctx), rest);
+                    }
+                }
+            }
+        }
+        Shape::Postfix { lbp } => {
+            concat(vec![node_doc(d, &c[0], Context { rbp: ctx.rbp, follow: lbp }), text(" "), text(&form.text)])
+        }
+        Shape::Call { lbp, ref sep, ref close } => {
+            let callee = node_doc(d, &c[0], Context { rbp: ctx.rbp, follow: lbp });
+            if c.len() == 1 {
+                return concat(vec![callee, text(&form.text), text(close)]);
+            }
+            let mut args = Vec::new();
+            for (i, arg) in c[1..].iter().enumerate() {
+                if i > 0 {
+                    args.push(text(&format!(" {}", sep)));
+                    args.push(Doc::Line);
+                }
+                args.push(node_doc(d, arg, FREE));
+            }
+            concat(vec![callee, group(concat(vec![
+                text(&form.text),
+                nest(INDENT, concat(vec![Doc::SoftLine, concat(args)])),
+                Doc::SoftLine,
+                text(close),
+            ]))])
+        }
+        Shape::PrefixMixfix { ref parts, rbp } => {
+            let first = concat(vec![text(&form.text), text(" "), node_doc(d, &c[0], FREE)]);
+            let mut rest = Vec::new();
+            for (i, part) in parts.iter().enumerate() {
+                let ctx = if i + 1 == parts.len() { Context { rbp, follow: ctx.follow } } else { FREE };
+                rest.push(concat(vec![text(part), text(" "), node_doc(d, &c[i + 1], ctx)]));
+            }
+            hanging(first, rest)
+        }
+        Shape::InfixMixfix { lbp, ref parts, rbp } => {
+            let first = node_doc(d, &c[0], Context { rbp: ctx.rbp, follow: lbp });
+            let mut rest = Vec::new();
+            let mut op = form.text.clone();
+            for (i, part) in parts.iter().enumerate() {
+                rest.push(concat(vec![text(&op), text(" "), node_doc(d, &c[i + 1], FREE)]));
+                op = part.clone();
+            }
+            rest.push(concat(vec![text(&op), text(" "), node_doc(d, &c[parts.len() + 1], Context { rbp, follow: ctx.follow })]));
+            hanging(first, rest)
+        }
+        Shape::Juxtaposition { lbp, rbp } => {
+            let left = node_doc(d, &c[0], Context { rbp: ctx.rbp, follow: lbp });
+            // the operator of "f - x" would be infix
+            let right = d.describe(&mut *c[1].borrow_mut());
+            let right = match right.shape {
+                Shape::Prefix { .. } => parenthesized(d, right),
+                _ => wrap(d, right, Context { rbp, follow: ctx.follow }),
+            };
+            hanging(left, vec![right])
+        }
+    }
+}
+
+pub fn doc<S: Symbol, D: Describe<S>>(d: &D, tree: &PrattBox<S>) -> Doc {
+    node_doc(d, tree, FREE)
+}
+
+//  everything on one line
+pub fn print<S: Symbol, D: Describe<S>>(d: &D, tree: &PrattBox<S>) -> String {
+    doc(d, tree).render(usize::MAX)
+}
+
+//  breaks operator chains, argument lists and mixfix parts to fit 'width'
+pub fn format<S: Symbol, D: Describe<S>>(d: &D, tree: &PrattBox<S>, width: usize) -> String {
+    doc(d, tree).render(width)
+}
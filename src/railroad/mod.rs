@@ -0,0 +1,261 @@
+use std::fmt::Write;
+use super::grammar::{Grammar, Rule, Infix, Prefix};
+use super::replay::escape;
+
+//  railroad (syntax) diagrams of a Grammar, written as plain svg text
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    Terminal(String),
+    NonTerminal(String),
+    Sequence(Vec<Node>),
+    // the first branch is the main line, the others hang below it
+    Choice(Vec<Node>),
+    Optional(Box<Node>),
+    // one or more, the separator is crossed on the way back
+    Repeat(Box<Node>, Option<Box<Node>>),
+    Skip,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagram {
+    pub name: String,
+    pub node: Node,
+}
+
+const CHAR: i64 = 8;
+const BOX: i64 = 11;
+const GAP: i64 = 10;
+const ARC: i64 = 10;
+const SIDE: i64 = 2 * ARC;
+
+//  width, height above and below the line the diagram is entered on
+struct Size {
+    w: i64,
+    up: i64,
+    down: i64,
+}
+
+impl Node {
+    fn size(&self) -> Size {
+        match *self {
+            Node::Terminal(ref s) | Node::NonTerminal(ref s) =>
+                Size { w: CHAR * s.chars().count() as i64 + 2 * GAP, up: BOX, down: BOX },
+            Node::Skip => Size { w: 0, up: 0, down: 0 },
+            Node::Sequence(ref nodes) => {
+                let mut size = Size { w: 0, up: 0, down: 0 };
+                for (i, n) in nodes.iter().enumerate() {
+                    let s = n.size();
+                    size.w += s.w + if i > 0 { GAP } else { 0 };
+                    size.up = size.up.max(s.up);
+                    size.down = size.down.max(s.down);
+                }
+                size
+            }
+            Node::Choice(ref nodes) => {
+                let mut size = Size { w: 0, up: 0, down: 0 };
+                for (i, n) in nodes.iter().enumerate() {
+                    let s = n.size();
+                    size.w = size.w.max(s.w);
+                    if i == 0 {
+                        size.up = s.up;
+                        size.down = s.down;
+                    } else {
+                        size.down = Node::branch_drop(size.down, &s) + s.down;
+                    }
+                }
+                size.w += 2 * SIDE;
+                size
+            }
+            Node::Optional(ref n) => Node::Choice(vec![Node::Skip, (**n).clone()]).size(),
+            Node::Repeat(ref n, ref sep) => {
+                let s = n.size();
+                let back = sep.as_ref().map_or(Node::Skip.size(), |sep| sep.size());
+                Size {
+                    w: s.w.max(back.w) + 2 * SIDE,
+                    up: s.up,
+                    down: Node::branch_drop(s.down, &back) + back.down,
+                }
+            }
+        }
+    }
+
+    //  distance from the main line to a branch hanging below 'down'
+    fn branch_drop(down: i64, branch: &Size) -> i64 {
+        (down + GAP + branch.up).max(2 * ARC)
+    }
+
+    fn render(&self, out: &mut String, x: i64, y: i64) {
+        match *self {
+            Node::Terminal(ref s) | Node::NonTerminal(ref s) => {
+                let w = self.size().w;
+                let rx = if let Node::Terminal(_) = *self { BOX } else { 0 };
+                let class = if rx > 0 { "terminal" } else { "nonterminal" };
+                let _ = writeln!(out, "<rect class=\"{}\" x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" rx=\"{}\"/>",
+                                 class, x, y - BOX, w, 2 * BOX, rx);
+                let _ = writeln!(out, "<text x=\"{}\" y=\"{}\">{}</text>", x + w / 2, y + 4, escape(s));
+            }
+            Node::Skip => {}
+            Node::Sequence(ref nodes) => {
+                let mut x = x;
+                for (i, n) in nodes.iter().enumerate() {
+                    if i > 0 {
+                        line(out, x, y, GAP);
+                        x += GAP;
+                    }
+                    n.render(out, x, y);
+                    x += n.size().w;
+                }
+            }
+            Node::Choice(ref nodes) => {
+                let size = self.size();
+                let mut down = 0;
+                for (i, n) in nodes.iter().enumerate() {
+                    let s = n.size();
+                    let dy = if i == 0 { 0 } else { Node::branch_drop(down, &s) };
+                    if i == 0 {
+                        down = s.down;
+                        line(out, x, y, SIDE);
+                    } else {
+                        down = dy + s.down;
+                        let _ = writeln!(out, "<path d=\"M{} {} q{} 0 {} {} v{} q0 {} {} {}\"/>",
+                                         x, y, ARC, ARC, ARC, dy - 2 * ARC, ARC, ARC, ARC);
+                    }
+                    n.render(out, x + SIDE, y + dy);
+                    let end = x + SIDE + s.w;
+                    line(out, end, y + dy, x + size.w - SIDE - end);
+                    if i == 0 {
+                        line(out, x + size.w - SIDE, y, SIDE);
+                    } else {
+                        let _ = writeln!(out, "<path d=\"M{} {} q{} 0 {} {} v{} q0 {} {} {}\"/>",
+                                         x + size.w - SIDE, y + dy, ARC, ARC, -ARC, -(dy - 2 * ARC), -ARC, ARC, -ARC);
+                    }
+                }
+            }
+            Node::Optional(ref n) => Node::Choice(vec![Node::Skip, (**n).clone()]).render(out, x, y),
+            Node::Repeat(ref n, ref sep) => {
+                let size = self.size();
+                let s = n.size();
+                line(out, x, y, SIDE);
+                n.render(out, x + SIDE, y);
+                line(out, x + SIDE + s.w, y, size.w - SIDE - s.w);
+                let back = sep.as_ref().map_or(Node::Skip.size(), |sep| sep.size());
+                let dy = Node::branch_drop(s.down, &back);
+                let right = x + size.w - SIDE;
+                let left = x + SIDE;
+                let _ = writeln!(out, "<path d=\"M{} {} q{} 0 {} {} v{} q0 {} {} {}\"/>",
+                                 right, y, ARC, ARC, ARC, dy - 2 * ARC, ARC, -ARC, ARC);
+                let _ = writeln!(out, "<path d=\"M{} {} q{} 0 {} {} v{} q0 {} {} {}\"/>",
+                                 left, y + dy, -ARC, -ARC, -ARC, -(dy - 2 * ARC), -ARC, ARC, -ARC);
+                let start = left + (right - left - back.w) / 2;
+                line(out, left, y + dy, start - left);
+                if let Some(ref sep) = *sep {
+                    sep.render(out, start, y + dy);
+                }
+                line(out, start + back.w, y + dy, right - start - back.w);
+            }
+        }
+    }
+}
+
+fn line(out: &mut String, x: i64, y: i64, w: i64) {
+    if w > 0 {
+        let _ = writeln!(out, "<path d=\"M{} {} h{}\"/>", x, y, w);
+    }
+}
+
+impl Diagram {
+    pub fn to_svg(&self) -> String {
+        let size = self.node.size();
+        let w = size.w + 2 * SIDE + 2 * GAP;
+        let h = size.up + size.down + 2 * GAP;
+        let y = GAP + size.up;
+        let mut out = String::new();
+        let _ = writeln!(out, "<svg xmlns=\"http://www.w3.org/2000/svg\" class=\"railroad\" width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\">", w, h, w, h);
+        let _ = writeln!(out, "<title>{}</title>", escape(&self.name));
+        out.push_str("<style>path { stroke: #333; stroke-width: 2; fill: none; } \
+                      rect { stroke: #333; stroke-width: 2; fill: #ffc; } \
+                      rect.nonterminal { fill: #eef; } \
+                      text { font: 14px monospace; text-anchor: middle; }</style>\n");
+        // entry and exit bars
+        let _ = writeln!(out, "<path d=\"M{} {} v{} m{} {} v{}\"/>", GAP, y - BOX / 2, BOX, ARC / 2, -BOX, BOX);
+        line(&mut out, GAP, y, SIDE);
+        self.node.render(&mut out, GAP + SIDE, y);
+        let end = GAP + SIDE + size.w;
+        line(&mut out, end, y, SIDE);
+        let _ = writeln!(out, "<path d=\"M{} {} v{} m{} {} v{}\"/>", end + SIDE - ARC / 2, y - BOX / 2, BOX, ARC / 2, -BOX, BOX);
+        out.push_str("</svg>\n");
+        out
+    }
+}
+
+
+fn t(s: &str) -> Node {
+    Node::Terminal(String::from(s))
+}
+
+fn expr() -> Node {
+    Node::NonTerminal(String::from("expr"))
+}
+
+fn parts(nodes: &mut Vec<Node>, parts: &[&'static str]) {
+    for part in parts {
+        nodes.push(expr());
+        nodes.push(t(part));
+    }
+    nodes.push(expr());
+}
+
+//  one diagram per construct of the rule: its prefix use and its infix use
+pub fn rule_diagrams(rule: &Rule) -> Vec<Diagram> {
+    let mut diagrams = Vec::new();
+    let prefix = match rule.prefix {
+        Some(Prefix::Operator { .. }) | Some(Prefix::Custom(_)) => Some(vec![t(rule.name), expr()]),
+        Some(Prefix::Group { close }) => Some(vec![t(rule.name), expr(), t(close)]),
+        Some(Prefix::Mixfix { parts: ref ps, .. }) => {
+            let mut nodes = vec![t(rule.name)];
+            parts(&mut nodes, ps);
+            Some(nodes)
+        }
+        Some(Prefix::Atom) | None => None,
+    };
+    if let Some(nodes) = prefix {
+        diagrams.push(Diagram { name: format!("prefix {}", rule.name), node: Node::Sequence(nodes) });
+    }
+    let infix = match rule.infix {
+        Some(Infix::Operator { .. }) | Some(Infix::Custom(_)) => Some(vec![expr(), t(rule.name), expr()]),
+        Some(Infix::Postfix) => Some(vec![expr(), t(rule.name)]),
+        Some(Infix::Call { sep, close }) => Some(vec![
+            expr(),
+            t(rule.name),
+            Node::Optional(Box::new(Node::Repeat(Box::new(expr()), Some(Box::new(t(sep)))))),
+            t(close),
+        ]),
+        Some(Infix::Mixfix { parts: ref ps, .. }) => {
+            let mut nodes = vec![expr(), t(rule.name)];
+            parts(&mut nodes, ps);
+            Some(nodes)
+        }
+        None => None,
+    };
+    if let Some(nodes) = infix {
+        diagrams.push(Diagram { name: format!("infix {}", rule.name), node: Node::Sequence(nodes) });
+    }
+    diagrams
+}
+
+pub fn diagrams(grammar: &Grammar) -> Vec<Diagram> {
+    grammar.rules().iter().flat_map(rule_diagrams).collect()
+}
+
+//  every way to write an expression: atoms, then each construct
+pub fn expression(grammar: &Grammar) -> Diagram {
+    let mut choices = Vec::new();
+    for rule in grammar.rules() {
+        if let Some(Prefix::Atom) = rule.prefix {
+            choices.push(Node::NonTerminal(String::from(rule.name)));
+        }
+    }
+    choices.extend(diagrams(grammar).into_iter().map(|d| d.node));
+    Diagram { name: String::from("expr"), node: Node::Choice(choices) }
+}
@@ -0,0 +1,172 @@
+use std::ops::Range;
+use std::sync::Arc;
+use super::green::{GreenElement, GreenNode, GreenToken};
+
+//  cursors over a green tree: a SyntaxNode is a green node plus where it
+//  is, its offset and its parent, worked out while walking down from the
+//  root; cursors are cheap to clone and can be sent to other threads
+
+struct NodeData {
+    green: Arc<GreenNode>,
+    parent: Option<SyntaxNode>,
+    // index in the parent's children
+    index: usize,
+    offset: usize,
+}
+
+#[derive(Clone)]
+pub struct SyntaxNode {
+    data: Arc<NodeData>,
+}
+
+#[derive(Clone)]
+pub struct SyntaxToken {
+    green: Arc<GreenToken>,
+    parent: SyntaxNode,
+    index: usize,
+    offset: usize,
+}
+
+#[derive(Clone)]
+pub enum SyntaxElement {
+    Node(SyntaxNode),
+    Token(SyntaxToken),
+}
+
+impl SyntaxNode {
+    pub fn new_root(green: Arc<GreenNode>) -> SyntaxNode {
+        SyntaxNode { data: Arc::new(NodeData { green, parent: None, index: 0, offset: 0 }) }
+    }
+
+    pub fn green(&self) -> &Arc<GreenNode> {
+        &self.data.green
+    }
+    pub fn kind(&self) -> &str {
+        self.data.green.kind()
+    }
+    pub fn parent(&self) -> Option<SyntaxNode> {
+        self.data.parent.clone()
+    }
+    pub fn index(&self) -> usize {
+        self.data.index
+    }
+    //  bytes of the source covered, trivia included
+    pub fn text_range(&self) -> Range<usize> {
+        self.data.offset..self.data.offset + self.data.green.len()
+    }
+    pub fn text(&self) -> String {
+        self.data.green.text()
+    }
+
+    pub fn ancestors(&self) -> Vec<SyntaxNode> {
+        let mut nodes = vec![];
+        let mut node = self.parent();
+        while let Some(n) = node {
+            node = n.parent();
+            nodes.push(n);
+        }
+        nodes
+    }
+
+    pub fn children_with_tokens(&self) -> Vec<SyntaxElement> {
+        let mut offset = self.data.offset;
+        let mut elements = Vec::with_capacity(self.data.green.children().len());
+        for (index, child) in self.data.green.children().iter().enumerate() {
+            elements.push(match *child {
+                GreenElement::Node(ref green) => SyntaxElement::Node(SyntaxNode {
+                    data: Arc::new(NodeData { green: green.clone(), parent: Some(self.clone()), index, offset }),
+                }),
+                GreenElement::Token(ref green) => SyntaxElement::Token(SyntaxToken {
+                    green: green.clone(), parent: self.clone(), index, offset,
+                }),
+            });
+            offset += child.len();
+        }
+        elements
+    }
+
+    pub fn children(&self) -> Vec<SyntaxNode> {
+        self.children_with_tokens().into_iter().filter_map(|e| match e {
+            SyntaxElement::Node(n) => Some(n),
+            SyntaxElement::Token(_) => None,
+        }).collect()
+    }
+
+    pub fn tokens(&self) -> Vec<SyntaxToken> {
+        self.children_with_tokens().into_iter().filter_map(|e| match e {
+            SyntaxElement::Token(t) => Some(t),
+            SyntaxElement::Node(_) => None,
+        }).collect()
+    }
+
+    //  every token under the node, in source order
+    pub fn descendant_tokens(&self) -> Vec<SyntaxToken> {
+        let mut tokens = vec![];
+        for element in self.children_with_tokens() {
+            match element {
+                SyntaxElement::Node(n) => tokens.extend(n.descendant_tokens()),
+                SyntaxElement::Token(t) => tokens.push(t),
+            }
+        }
+        tokens
+    }
+
+    //  the deepest node whose text covers 'range'
+    pub fn covering_node(&self, range: Range<usize>) -> SyntaxNode {
+        let mut node = self.clone();
+        'down: loop {
+            for child in node.children() {
+                let r = child.text_range();
+                if r.start <= range.start && range.end <= r.end && r.start < r.end {
+                    node = child;
+                    continue 'down;
+                }
+            }
+            return node;
+        }
+    }
+
+    //  the green root of a tree where this node is 'green' instead,
+    //  everything off the path to the root is shared with the old tree
+    pub fn replace_with(&self, green: Arc<GreenNode>) -> Arc<GreenNode> {
+        match self.parent() {
+            None => green,
+            Some(parent) => {
+                let new = parent.green().replace_child(self.index(), GreenElement::Node(green));
+                parent.replace_with(Arc::new(new))
+            }
+        }
+    }
+}
+
+impl SyntaxToken {
+    pub fn green(&self) -> &Arc<GreenToken> {
+        &self.green
+    }
+    pub fn kind(&self) -> &str {
+        self.green.kind()
+    }
+    pub fn parent(&self) -> SyntaxNode {
+        self.parent.clone()
+    }
+    pub fn index(&self) -> usize {
+        self.index
+    }
+    //  the token without its trivia
+    pub fn text(&self) -> &str {
+        self.green.text()
+    }
+    pub fn text_range(&self) -> Range<usize> {
+        let start = self.offset + self.green.leading();
+        start..start + self.green.text().len()
+    }
+    pub fn full_range(&self) -> Range<usize> {
+        self.offset..self.offset + self.green.len()
+    }
+}
+
+impl PartialEq for SyntaxNode {
+    fn eq(&self, other: &SyntaxNode) -> bool {
+        Arc::ptr_eq(&self.data.green, &other.data.green) && self.data.offset == other.data.offset
+    }
+}
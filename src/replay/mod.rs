@@ -0,0 +1,303 @@
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::fmt::Write;
+use super::{PrattBox, Symbol};
+use super::trace::{TraceEvent, Tracer};
+
+//  records a whole parse session as a list of steps,
+//  to be replayed as a text table or a self-contained html page
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    pub step: usize,
+    pub depth: usize,
+    pub token: String,
+    pub rbp: Option<u8>,
+    pub lbp: Option<u8>,
+    pub action: String,
+    pub tree: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    pub steps: Vec<Step>,
+}
+
+//  a nud or led that has not returned yet: its token, the operands it
+//  has and the one being parsed
+#[derive(Default)]
+struct Frame {
+    head: String,
+    done: Vec<String>,
+    inner: Option<String>,
+}
+
+pub struct Recorder {
+    steps: RefCell<Vec<Step>>,
+    current: RefCell<String>,
+    // the top level parse, then every pending nud/led
+    frames: RefCell<Vec<Frame>>,
+    depth: Cell<usize>,
+}
+
+impl Recorder {
+    pub fn new() -> Recorder {
+        Recorder {
+            steps: RefCell::new(Vec::new()),
+            current: RefCell::new(String::new()),
+            frames: RefCell::new(vec![Frame::default()]),
+            depth: Cell::new(0),
+        }
+    }
+
+    //  the whole partial tree: every pending nud/led as an s-expression
+    //  with the operand being parsed as its last child
+    fn tree(&self) -> String {
+        let frames = self.frames.borrow();
+        let mut tree = frames.last().and_then(|f| f.inner.clone());
+        for frame in frames.iter().skip(1).rev() {
+            let mut parts = vec![frame.head.clone()];
+            parts.extend(frame.done.iter().cloned());
+            parts.extend(tree);
+            tree = Some(if parts.len() == 1 { parts.remove(0) } else { format!("({})", parts.join(" ")) });
+        }
+        tree.unwrap_or_default()
+    }
+
+    //  a new nud/led: the last operand of the frame it is in is complete
+    fn call(&self, head: String, done: Vec<String>) {
+        let mut frames = self.frames.borrow_mut();
+        if let Some(frame) = frames.last_mut() {
+            if let Some(inner) = frame.inner.take() {
+                frame.done.push(inner);
+            }
+        }
+        frames.push(Frame { head, done, inner: None });
+    }
+
+    fn operand(&self, node: String) {
+        if let Some(frame) = self.frames.borrow_mut().last_mut() {
+            frame.inner = Some(node);
+        }
+    }
+
+    pub fn session(&self) -> Session {
+        Session { steps: self.steps.borrow().clone() }
+    }
+
+    fn push(&self, rbp: Option<u8>, lbp: Option<u8>, action: String) {
+        let mut steps = self.steps.borrow_mut();
+        let step = steps.len() + 1;
+        steps.push(Step {
+            step,
+            depth: self.depth.get(),
+            token: self.current.borrow().clone(),
+            rbp,
+            lbp,
+            action,
+            tree: self.tree(),
+        });
+    }
+}
+
+impl Default for Recorder {
+    fn default() -> Recorder {
+        Recorder::new()
+    }
+}
+
+fn show<S: fmt::Display>(node: &PrattBox<S>) -> String {
+    format!("{}", *node.borrow_mut())
+}
+
+impl<S: Symbol + fmt::Display> Tracer<S> for Recorder {
+    fn trace(&self, event: &TraceEvent<S>) {
+        match *event {
+            TraceEvent::Advance(t) => {
+                *self.current.borrow_mut() = show(t);
+                self.push(None, None, String::from("advance"));
+            }
+            TraceEvent::Relex(t) => {
+                *self.current.borrow_mut() = show(t);
+                self.push(None, None, String::from("relex"));
+            }
+            TraceEvent::Inserted(t) => {
+                *self.current.borrow_mut() = show(t);
+                self.push(None, None, String::from("insert"));
+            }
+            TraceEvent::Nud(t) => {
+                self.call(show(t), vec![]);
+                self.push(None, None, format!("nud {}", show(t)));
+                self.depth.set(self.depth.get() + 1);
+            }
+            TraceEvent::Led(t, left) => {
+                // the left operand moves from the frame it is in to the led
+                if let Some(frame) = self.frames.borrow_mut().last_mut() {
+                    frame.inner = None;
+                }
+                self.call(show(t), vec![show(left)]);
+                self.push(None, None, format!("led {}", show(t)));
+                self.depth.set(self.depth.get() + 1);
+            }
+            TraceEvent::Compare { rbp, lbp, lookahead, left, binds } => {
+                self.operand(show(left));
+                let action = if binds { "continue with" } else { "stop before" };
+                self.push(Some(rbp), Some(lbp), format!("{} {}", action, show(lookahead)));
+            }
+            TraceEvent::Return(node) => {
+                self.depth.set(self.depth.get().saturating_sub(1));
+                {
+                    let mut frames = self.frames.borrow_mut();
+                    if frames.len() > 1 {
+                        frames.pop();
+                    }
+                }
+                self.operand(show(node));
+                self.push(None, None, String::from("return"));
+            }
+        }
+    }
+}
+
+
+fn opt(v: Option<u8>) -> String {
+    v.map(|v| v.to_string()).unwrap_or_default()
+}
+
+pub(crate) fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+const HEADERS: [&str; 6] = ["step", "token", "rbp", "lbp", "action", "tree"];
+
+impl Session {
+    fn rows(&self) -> Vec<[String; 6]> {
+        self.steps.iter().map(|s| {
+            [s.step.to_string(),
+             s.token.clone(),
+             opt(s.rbp),
+             opt(s.lbp),
+             format!("{:1$}{2}", "", s.depth * 2, s.action),
+             s.tree.clone()]
+        }).collect()
+    }
+
+    pub fn to_text(&self) -> String {
+        let rows = self.rows();
+        let mut widths = [0; 6];
+        for (i, h) in HEADERS.iter().enumerate() {
+            widths[i] = h.len();
+        }
+        for row in &rows {
+            for (i, cell) in row.iter().enumerate() {
+                widths[i] = widths[i].max(cell.chars().count());
+            }
+        }
+        let mut out = String::new();
+        let line = |out: &mut String, cells: &[String]| {
+            let mut l = String::new();
+            for (i, cell) in cells.iter().enumerate() {
+                let _ = write!(l, "| {:1$} ", cell, widths[i]);
+            }
+            l.push('|');
+            out.push_str(l.trim_end());
+            out.push('\n');
+        };
+        let headers: Vec<String> = HEADERS.iter().map(|h| h.to_string()).collect();
+        line(&mut out, &headers);
+        let rule: Vec<String> = widths.iter().map(|w| "-".repeat(*w)).collect();
+        line(&mut out, &rule);
+        for row in &rows {
+            line(&mut out, row);
+        }
+        out
+    }
+
+    pub fn to_html(&self) -> String {
+        let mut out = String::new();
+        out.push_str(HTML_HEAD);
+        out.push_str("<table>\n<tr>");
+        for h in HEADERS.iter() {
+            let _ = write!(out, "<th>{}</th>", h);
+        }
+        out.push_str("</tr>\n");
+        for row in self.rows() {
+            out.push_str("<tr class=\"step\">");
+            for cell in row.iter() {
+                let _ = write!(out, "<td>{}</td>", escape(cell));
+            }
+            out.push_str("</tr>\n");
+        }
+        out.push_str("</table>\n");
+        out.push_str(HTML_TAIL);
+        out
+    }
+}
+
+const HTML_HEAD: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>pratt parse replay</title>
+<style>
+body { font-family: monospace; }
+td, th { padding: 0 1em; text-align: left; white-space: pre; }
+tr.step { color: #bbb; }
+tr.step.done { color: #000; }
+tr.step.now { background: #ffd; color: #000; font-weight: bold; }
+#tree { margin: 1em 0; padding: 0.5em; border: 1px solid #ccc; min-height: 1.2em; }
+</style>
+</head>
+<body>
+<div>
+<button id="first">|&lt;</button>
+<button id="back">&lt; back</button>
+<button id="forward">forward &gt;</button>
+<button id="last">&gt;|</button>
+<span id="pos"></span>
+</div>
+<div id="tree"></div>
+"#;
+
+const HTML_TAIL: &str = r#"<script>
+(function () {
+    var rows = document.querySelectorAll("tr.step");
+    var now = 0;
+    function show() {
+        for (var i = 0; i < rows.length; i++) {
+            rows[i].className = "step" + (i < now ? " done" : "") + (i == now ? " now" : "");
+        }
+        if (rows.length > 0) {
+            document.getElementById("tree").textContent = rows[now].cells[5].textContent;
+        }
+        document.getElementById("pos").textContent = (now + 1) + " / " + rows.length;
+    }
+    function go(i) {
+        now = Math.max(0, Math.min(rows.length - 1, i));
+        show();
+    }
+    document.getElementById("first").onclick = function () { go(0); };
+    document.getElementById("back").onclick = function () { go(now - 1); };
+    document.getElementById("forward").onclick = function () { go(now + 1); };
+    document.getElementById("last").onclick = function () { go(rows.length - 1); };
+    document.onkeydown = function (e) {
+        if (e.key == "ArrowLeft") { go(now - 1); }
+        if (e.key == "ArrowRight") { go(now + 1); }
+    };
+    show();
+})();
+</script>
+</body>
+</html>
+"#;
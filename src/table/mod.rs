@@ -0,0 +1,139 @@
+use std::fmt;
+use std::fmt::Write;
+use super::grammar::{Assoc, Grammar, Infix, Prefix};
+use super::replay::escape;
+
+//  operator precedence table of a Grammar, tightest binding first,
+//  for language docs that stay in sync with the parser
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fixity {
+    Prefix,
+    Infix,
+    Postfix,
+    Group,
+    Mixfix,
+}
+
+impl fmt::Display for Fixity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            Fixity::Prefix => "prefix",
+            Fixity::Infix => "infix",
+            Fixity::Postfix => "postfix",
+            Fixity::Group => "group",
+            Fixity::Mixfix => "mixfix",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Row {
+    // 1 binds tightest, rows with equal binding power share a level
+    pub level: usize,
+    pub operator: String,
+    pub fixity: Fixity,
+    pub assoc: Option<Assoc>,
+    // lbp for infix and postfix, rbp of the operand for prefix
+    pub bp: Option<u8>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PrecedenceTable {
+    pub rows: Vec<Row>,
+}
+
+fn mixfix(name: &str, parts: &[&str]) -> String {
+    let mut s = String::from(name);
+    for part in parts {
+        s.push(' ');
+        s.push_str(part);
+    }
+    s
+}
+
+fn row(operator: String, fixity: Fixity, assoc: Option<Assoc>, bp: Option<u8>) -> Row {
+    Row { level: 0, operator, fixity, assoc, bp }
+}
+
+impl PrecedenceTable {
+    pub fn new(grammar: &Grammar) -> PrecedenceTable {
+        let mut rows = Vec::new();
+        for rule in grammar.rules() {
+            match rule.prefix {
+                Some(Prefix::Operator { rbp }) =>
+                    rows.push(row(rule.name.to_string(), Fixity::Prefix, None, Some(rbp))),
+                Some(Prefix::Group { close }) =>
+                    rows.push(row(format!("{} {}", rule.name, close), Fixity::Group, None, None)),
+                Some(Prefix::Mixfix { ref parts, rbp }) =>
+                    rows.push(row(mixfix(rule.name, parts), Fixity::Mixfix, None, Some(rbp))),
+                Some(Prefix::Custom(_)) =>
+                    rows.push(row(rule.name.to_string(), Fixity::Prefix, None, None)),
+                Some(Prefix::Atom) | None => {}
+            }
+            match rule.infix {
+                Some(Infix::Operator { .. }) | Some(Infix::Custom(_)) =>
+                    rows.push(row(rule.name.to_string(), Fixity::Infix, rule.assoc(), Some(rule.lbp))),
+                Some(Infix::Postfix) =>
+                    rows.push(row(rule.name.to_string(), Fixity::Postfix, None, Some(rule.lbp))),
+                Some(Infix::Call { close, .. }) =>
+                    rows.push(row(format!("{} {}", rule.name, close), Fixity::Postfix, None, Some(rule.lbp))),
+                Some(Infix::Mixfix { ref parts, .. }) =>
+                    rows.push(row(mixfix(rule.name, parts), Fixity::Mixfix, rule.assoc(), Some(rule.lbp))),
+                None => {}
+            }
+        }
+        // groups first, then by binding power and custom prefixes, which
+        // have none, last; the sort is stable so operators of the same
+        // level keep their registration order
+        rows.sort_by_key(|r| (r.fixity != Fixity::Group, r.bp.is_none(), r.bp.map(|bp| !bp)));
+        let mut level = 0;
+        let mut last = None;
+        for r in rows.iter_mut() {
+            let key = (r.fixity == Fixity::Group, r.bp);
+            if last != Some(key) {
+                level += 1;
+                last = Some(key);
+            }
+            r.level = level;
+        }
+        PrecedenceTable { rows }
+    }
+
+    fn cells(r: &Row) -> [String; 5] {
+        [r.level.to_string(),
+         r.operator.clone(),
+         r.fixity.to_string(),
+         match r.assoc {
+             Some(Assoc::Left) => String::from("left"),
+             Some(Assoc::Right) => String::from("right"),
+             None => String::new(),
+         },
+         r.bp.map(|bp| bp.to_string()).unwrap_or_default()]
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let mut out = String::from("| Level | Operator | Fixity | Associativity | Binding power |\n");
+        out.push_str("|---|---|---|---|---|\n");
+        for r in &self.rows {
+            let c = PrecedenceTable::cells(r);
+            let op: Vec<String> = c[1].split(' ').map(|o| format!("`{}`", o.replace('|', "\\|"))).collect();
+            let _ = writeln!(out, "| {} | {} | {} | {} | {} |", c[0], op.join(" "), c[2], c[3], c[4]);
+        }
+        out
+    }
+
+    pub fn to_html(&self) -> String {
+        let mut out = String::from("<table class=\"precedence\">\n");
+        out.push_str("<tr><th>Level</th><th>Operator</th><th>Fixity</th><th>Associativity</th><th>Binding power</th></tr>\n");
+        for r in &self.rows {
+            let c = PrecedenceTable::cells(r);
+            let op: Vec<String> = c[1].split(' ').map(|o| format!("<code>{}</code>", escape(o))).collect();
+            let _ = writeln!(out, "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                             c[0], op.join(" "), c[2], c[3], c[4]);
+        }
+        out.push_str("</table>\n");
+        out
+    }
+}
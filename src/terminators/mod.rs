@@ -0,0 +1,183 @@
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use super::{PrattBox, Symbol, Tokenizer, CancelHandle};
+use super::lexer::{FnewToken, Lexeme};
+
+//  a tokenizer over another one that puts in a terminator at a line break
+//  where a statement can end: after a token that can end an expression and
+//  before one that cannot go on with it, as Go and JavaScript do with ';'.
+//  The tokenizer underneath has to set newline_before on the lexemes
+
+pub struct Terminators<S: Symbol, T: Tokenizer<S>> {
+    tokens: T,
+    // the terminator made up, made by 'factory' from 'kind'
+    kind: &'static str,
+    factory: FnewToken<S>,
+    // kinds that can end an expression
+    ends: HashSet<String>,
+    // kinds that go on with an expression or not, whatever their lbp
+    continues: HashMap<String, bool>,
+    // the current token when it was made up
+    inserted: RefCell<Option<PrattBox<S>>>,
+    // the end of input got its terminator
+    closed: Cell<bool>,
+}
+
+fn lexeme<S: Symbol>(t: &PrattBox<S>) -> Option<Lexeme> {
+    t.borrow_mut().lexeme().cloned()
+}
+
+impl<S: Symbol, T: Tokenizer<S>> Terminators<S, T> {
+    pub fn new(tokens: T, kind: &'static str, factory: FnewToken<S>) -> Terminators<S, T> {
+        Terminators {
+            tokens, kind, factory,
+            ends: HashSet::new(),
+            continues: HashMap::new(),
+            inserted: RefCell::new(None),
+            closed: Cell::new(false),
+        }
+    }
+
+    //  a line break after a token of 'kind' can end the statement
+    pub fn end_after(&mut self, kind: &str) {
+        self.ends.insert(String::from(kind));
+    }
+
+    //  by default a token goes on with the expression before it when its
+    //  lbp is not 0, i.e. when it has a led, and the led is not restricted
+    pub fn continue_with(&mut self, kind: &str, continues: bool) {
+        self.continues.insert(String::from(kind), continues);
+    }
+
+    pub fn into_inner(self) -> T {
+        self.tokens
+    }
+
+    fn continues(&self, next: &PrattBox<S>, kind: &str) -> bool {
+        match self.continues.get(kind) {
+            Some(&continues) => continues,
+            None => {
+                let mut next = next.borrow_mut();
+                next.lbp() > 0 && !next.restricted()
+            }
+        }
+    }
+
+    //  the terminator right after 'prev', empty in the source
+    fn insert(&self, prev: &Lexeme) {
+        let token = (self.factory)(self.kind);
+        if let Some(l) = token.borrow_mut().lexeme() {
+            *l = Lexeme {
+                kind: String::from(self.kind),
+                span: prev.span.end..prev.span.end,
+                ..Lexeme::default()
+            };
+        }
+        *self.inserted.borrow_mut() = Some(token);
+    }
+}
+
+impl<S: Symbol, T: Tokenizer<S>> Tokenizer<S> for Terminators<S, T> {
+    fn advance(&self) {
+        if self.inserted.borrow_mut().take().is_some() {
+            return;
+        }
+        let prev = self.tokens.current().and_then(|t| lexeme(&t));
+        self.tokens.advance();
+        let prev = match prev {
+            Some(prev) => prev,
+            None => return,
+        };
+        if !self.ends.contains(&prev.kind) {
+            return;
+        }
+        if self.tokens.exhausted() {
+            if !self.closed.get() {
+                self.closed.set(true);
+                self.insert(&prev);
+            }
+            return;
+        }
+        let next = match self.tokens.current() {
+            Some(next) => next,
+            None => return,
+        };
+        let kind = lexeme(&next).and_then(|l| if l.newline_before { Some(l.kind) } else { None });
+        if let Some(kind) = kind {
+            if !self.continues(&next, &kind) {
+                self.insert(&prev);
+            }
+        }
+    }
+
+    fn current(&self) -> Option<PrattBox<S>> {
+        match *self.inserted.borrow() {
+            Some(ref t) => Some(t.clone()),
+            None => self.tokens.current(),
+        }
+    }
+
+    //  terminators further on are not known yet
+    fn peek(&self, n: usize) -> Option<PrattBox<S>> {
+        if self.inserted.borrow().is_some() {
+            if n == 0 { self.current() } else { self.tokens.peek(n - 1) }
+        } else {
+            self.tokens.peek(n)
+        }
+    }
+
+    fn set_cancel_handle(&self, cancel: CancelHandle) {
+        self.tokens.set_cancel_handle(cancel);
+    }
+
+    fn exhausted(&self) -> bool {
+        self.inserted.borrow().is_none() && self.tokens.exhausted()
+    }
+
+    //  not while on a terminator made up
+    fn checkpoint(&self) -> Option<Box<dyn Any>> {
+        if self.inserted.borrow().is_some() {
+            return None;
+        }
+        let checkpoint = self.tokens.checkpoint()?;
+        Some(Box::new((checkpoint, self.closed.get())))
+    }
+
+    fn rewind(&self, checkpoint: &dyn Any) -> bool {
+        let &(ref checkpoint, closed) = match checkpoint.downcast_ref::<(Box<dyn Any>, bool)>() {
+            Some(checkpoint) => checkpoint,
+            None => return false,
+        };
+        if !self.tokens.rewind(&**checkpoint) {
+            return false;
+        }
+        *self.inserted.borrow_mut() = None;
+        self.closed.set(closed);
+        true
+    }
+
+    fn push_mode(&self, mode: &'static str) {
+        self.tokens.push_mode(mode);
+    }
+
+    fn pop_mode(&self) {
+        self.tokens.pop_mode();
+    }
+
+    fn inserted(&self) -> bool {
+        self.inserted.borrow().is_some()
+    }
+
+    fn take_error(&self) -> Option<String> {
+        self.tokens.take_error()
+    }
+
+    fn missing(&self) -> Option<PrattBox<S>> {
+        self.tokens.missing()
+    }
+
+    fn juxtaposition(&self) -> Option<PrattBox<S>> {
+        self.tokens.juxtaposition()
+    }
+}
@@ -0,0 +1,87 @@
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::io::Write;
+use super::{PrattBox, Symbol};
+
+//  what Pratt::parse decided, in the order it decided it; more kinds of
+//  events may come, tracers need a catch-all arm
+#[non_exhaustive]
+pub enum TraceEvent<'a, S: Symbol + 'a> {
+    // the tokenizer moved on, the token is the new current one
+    Advance(&'a PrattBox<S>),
+    // the current token was lexed again, in a new lexer mode
+    Relex(&'a PrattBox<S>),
+    // like Advance, to a token the tokenizer made up
+    Inserted(&'a PrattBox<S>),
+    Nud(&'a PrattBox<S>),
+    // operator and left operand
+    Led(&'a PrattBox<S>, &'a PrattBox<S>),
+    // rbp < lbp decides whether the lookahead takes 'left' as its left operand
+    Compare { rbp: u8, lbp: u8, lookahead: &'a PrattBox<S>, left: &'a PrattBox<S>, binds: bool },
+    // node returned by the last nud or led
+    Return(&'a PrattBox<S>),
+}
+
+pub trait Tracer<S: Symbol> {
+    fn trace(&self, event: &TraceEvent<S>);
+}
+
+
+//  writes one line per event, nested nud/led calls indented
+pub struct PrettyTracer<W: Write> {
+    out: RefCell<W>,
+    depth: Cell<usize>,
+}
+
+impl<W: Write> PrettyTracer<W> {
+    pub fn new(out: W) -> PrettyTracer<W> {
+        PrettyTracer { out: RefCell::new(out), depth: Cell::new(0) }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out.into_inner()
+    }
+
+    fn line(&self, args: fmt::Arguments) {
+        let mut out = self.out.borrow_mut();
+        let _ = write!(out, "{:1$}", "", self.depth.get() * 2);
+        let _ = out.write_fmt(args);
+        let _ = writeln!(out);
+    }
+}
+
+impl<S: Symbol + fmt::Display, W: Write> Tracer<S> for PrettyTracer<W> {
+    fn trace(&self, event: &TraceEvent<S>) {
+        match *event {
+            TraceEvent::Advance(t) => {
+                self.line(format_args!("advance {}", *t.borrow_mut()));
+            }
+            TraceEvent::Relex(t) => {
+                self.line(format_args!("relex {}", *t.borrow_mut()));
+            }
+            TraceEvent::Inserted(t) => {
+                self.line(format_args!("insert {}", *t.borrow_mut()));
+            }
+            TraceEvent::Nud(t) => {
+                self.line(format_args!("nud {}", *t.borrow_mut()));
+                self.depth.set(self.depth.get() + 1);
+            }
+            TraceEvent::Led(t, left) => {
+                self.line(format_args!("led {} left {}", *t.borrow_mut(), *left.borrow_mut()));
+                self.depth.set(self.depth.get() + 1);
+            }
+            TraceEvent::Compare { rbp, lbp, lookahead, binds, .. } => {
+                self.line(format_args!("rbp {} {} lbp {} of {}: {}",
+                                       rbp,
+                                       if binds { "<" } else { ">=" },
+                                       lbp,
+                                       *lookahead.borrow_mut(),
+                                       if binds { "led" } else { "stop" }));
+            }
+            TraceEvent::Return(node) => {
+                self.depth.set(self.depth.get().saturating_sub(1));
+                self.line(format_args!("-> {}", *node.borrow_mut()));
+            }
+        }
+    }
+}
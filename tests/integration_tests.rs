@@ -6,237 +6,29 @@ extern crate pratt;
 
 use std::cell::RefCell;
 use std::rc::Rc;
-use std::collections::HashMap;
+use std::sync::Arc;
 
 #[cfg(feature="gc3c")]
 use gc3c::{InGcEnv, gc, Mark};
 
-use pratt::{PrattBox, Token, Symbol, Pratt, Tokenizer};
-use pratt::dyn::{DynamicToken, DynamicSymbol };
+use pratt::{PrattBox, Token, Symbol, Tokenizer, Pratt, AsPratt, BoxedPratt, ParseError, CancelHandle};
+use pratt::dyn::{DynamicToken, DynamicSymbol, TokenExtra, Fnud, Fled};
+use pratt::lexer::{StringTokenizer, Lexeme, Edit};
+use pratt::trace::PrettyTracer;
+use pratt::replay::Recorder;
+use pratt::grammar::{Grammar, Assoc, Prefix, Issue};
+use pratt::table::PrecedenceTable;
+use pratt::railroad::{self, Node};
+use pratt::printer;
+use pratt::cst::{self, Ast};
+use pratt::events::{self, Event, DynamicBuilder, EventTracer, Sink};
+use pratt::green::{self, GreenBuilder, GreenElement, NodeCache};
+use pratt::red::SyntaxNode;
+use pratt::incremental::Document;
+use pratt::terminators::Terminators;
     
     
 
-type FnewToken<S> = Box<dyn Fn(&str) -> PrattBox<S>>;
-   
-#[derive(Debug, PartialEq)]
-enum TokenStatus {
-    Init,
-    InToken,
-    InQuote,
-    InString,
-    InNum,
-    EndToken,
-}
- 
-struct TokenizerStatus {
-    status: TokenStatus,
-    i: usize,
-    j: usize,
-    inquotepar: u32,
-}
-
-struct StringTokenizer<S: Symbol> {
-    input: String,
-    tokens : RefCell<Vec<PrattBox<S>>>,
-    map: RefCell<HashMap<&'static str, FnewToken<S>>>,
-    st: RefCell<TokenizerStatus>,
-}
-
-impl<S: Symbol> StringTokenizer<S> {
-    fn new(input: String) -> StringTokenizer<S> {
-        StringTokenizer {
-            input: input,
-            st: RefCell::new(
-                TokenizerStatus {
-                          status: TokenStatus::Init, 
-                          i: 0, j: 0,
-                          inquotepar: 0,
-                }
-            ),
-            tokens: RefCell::new(Vec::new()),
-            map: RefCell::new(HashMap::new()),
-        }
-    }
-    fn new_token(&self, typ: &str, s: &str) -> PrattBox<S> {
-        if let Some(ref f ) = self.map.borrow().get(typ) {
-            f(s)
-        } else {
-            unreachable!("cannot handle token");
-        }
-    }
-
-    fn register_token(& self, s: &'static str, f: FnewToken<S>) {
-        self.map.borrow_mut().insert(s, f);
-    }
-}
-
-impl<S: Symbol> Tokenizer<S> for StringTokenizer<S> {
-
-    fn current(&self) -> Option<PrattBox<S>> {
-        let tokens = self.tokens.borrow();
-        tokens.last().map(|e| { e.clone() }) 
-    }
-
-    fn advance(& self)  {
-        let mut st = self.st.borrow_mut();
-        while st.j < self.input.len() && st.i < self.input.len() {
-            match st.status { 
-                TokenStatus::Init => {
-                    println!("1: {:?}", &self.input[st.j..st.j+1]);
-                    match &self.input[st.j..st.j+1] {
-                        " " | "\t" | "\n"  => {
-                            st.j = st.j + 1;
-                            st.i = st.j;
-                        }
-                        "'" => {
-                            st.j = st.j + 1;
-                            st.i = st.j;
-                            st.status = TokenStatus::InQuote;
-                        }
-                        "\"" => {
-                            st.j = st.j + 1;
-                            st.status = TokenStatus::InString;
-                        }
-                        "-" => {
-                            match &self.input[st.j+1..st.j+2] { 
-                                "." | "0" | "1" | "2" | "3" 
-                                    | "4" | "5" | "6" | "7" 
-                                    | "8" | "9" => {
-                                    st.j = st.j + 1;
-                                    st.status = TokenStatus::InNum;
-                                }
-                                _ => {
-                                    st.j = st.j + 1;
-                                    if let Some(ref f ) = self.map.borrow().get(&self.input[st.i..st.j]) {
-                                        self.tokens.borrow_mut().push(f(&self.input[st.i..st.j]));
-                                    } else {
-                                        self.tokens.borrow_mut().push(self.new_token("literal", &self.input[st.i..st.j]));    
-                                    }
-                                    st.status = TokenStatus::EndToken;
-                                }
-                            }
-                        }
-                        "." => {
-                            st.j = st.j + 1;
-                            if st.j >= self.input.len() {
-                                self.tokens.borrow_mut().push(self.new_token("end", &self.input[st.i..st.j]));
-                                st.status = TokenStatus::EndToken;
-                            } else {
-                                st.status = TokenStatus::InNum;
-                            }
-                        }
-                        "0" | "1" | "2" | "3" 
-                            | "4" | "5" | "6" | "7" 
-                            | "8" | "9" => {
-                            st.j = st.j + 1;
-                            st.status = TokenStatus::InNum;
-                        }
-                        _ => {
-                            if let Some(ref f ) = self.map.borrow().get(&self.input[st.j..st.j+1]) {
-                                self.tokens.borrow_mut().push(f(&self.input[st.j..st.j+1]));
-                                st.j = st.j + 1;
-                                st.status = TokenStatus::EndToken;
-                            } else {
-                                st.j = st.j + 1;
-                                st.status = TokenStatus::InToken;
-                            }
-                        }
-                    }
-                }
-    
-                TokenStatus::InNum => {
-                    match &self.input[st.j..st.j+1] {
-                        " " | "\t" | "\n" | "'" | "(" | ")" | "\"" => {
-                            self.tokens.borrow_mut().push(self.new_token("num", &self.input[st.i..st.j]));    
-                            st.j = st.j - 1;
-                            st.status = TokenStatus::EndToken;
-                        }
-                        "." | "0" | "1" | "2" | "3" 
-                            | "4" | "5" | "6" | "7" 
-                            | "8" | "9" => { st.j = st.j + 1; }
-                        _ => {
-                            println!("parse num error");
-                            unreachable!();
-                        }
-                    }
-                }
-    
-    
-                TokenStatus::InToken => {
-                    match &self.input[st.j..st.j+1] {
-                        " " | "\t" | "\n" | "'" | "(" | ")" | "\"" => {
-                            let s =&self.input[st.i..st.j];
-                            if let Some(ref f ) = self.map.borrow().get(s) {
-                                 self.tokens.borrow_mut().push(f(s));
-                            } else {
-                                 self.tokens.borrow_mut().push(self.new_token("literal", s));    
-                            }
-                            st.j = st.j - 1;
-                            st.status = TokenStatus::EndToken;
-                        }
-                        _ => { st.j = st.j + 1; }
-                    }
-                }
-    
-                TokenStatus::InString => {
-                    match &self.input[st.j..st.j+1] {
-                        "\""  => {
-                            let s = &self.input[st.i..st.j];    
-                            self.tokens.borrow_mut().push(self.new_token("string", s));    
-                            st.status = TokenStatus::EndToken;
-                        }
-                        _ => { st.j = st.j + 1; }
-                    }
-                }
-    
-                TokenStatus::InQuote => {
-                    let mut ret = false;
-                    match &self.input[st.j..st.j+1] {
-                        " " | "\t" | "\n" => {
-                            if  st.inquotepar == 0 {
-                                ret = true;
-                            } else {
-                                st.j = st.j + 1;
-                            }
-                        }
-                        "(" => {
-                            st.inquotepar = st.inquotepar + 1; 
-                            st.j = st.j + 1; 
-                        }
-                        ")" => {
-                            if  st.inquotepar == 0 {
-                                ret = true;
-                            } else {
-                                st.inquotepar = st.inquotepar - 1; 
-                                st.j = st.j + 1; 
-                            }
-                        }
-                        _ => { st.j = st.j + 1; }
-                    }
-                    if ret {
-                        
-                        let s = &self.input[st.i..st.j];
-                        self.tokens.borrow_mut().push(self.new_token("string", s));    
-                        
-                        st.j = st.j - 1;
-                        st.status = TokenStatus::EndToken;
-                    }
-                }
-                TokenStatus::EndToken => {
-                    //v.push(token.take().unwrap());    
-                    st.j = st.j + 1;
-                    st.i = st.j;
-                    st.status = TokenStatus::Init;
-                    break;
-                }
-            }
-        }
-    }
-}
-
-
-   
-
 #[derive(Debug)]
 struct EndToken {
     lbp: u8,
@@ -508,6 +300,7 @@ fn test_static() {
                                                      children: vec![],
                                                      fnud: Rc::new(|_se, _this, _pratt| { unreachable!(); }),
                                                      fled: Rc::new(|_se, _this, _pratt, _left| { unreachable!(); }),
+                                                     extra: TokenExtra::default(),
                                                  }
                                       }
                                   )
@@ -524,6 +317,7 @@ fn test_static() {
                                                      children: vec![],
                                                      fnud: Rc::new(|_se, this, _pratt| { this }),
                                                      fled: Rc::new(|_se, _this, _pratt, _left| { unreachable!(); }),
+                                                     extra: TokenExtra::default(),
                                                  }
                                       }
                                   )
@@ -540,6 +334,7 @@ fn test_static() {
                                                      children: vec![],
                                                      fnud: Rc::new(|_se, this, _pratt| { this }),
                                                      fled: Rc::new(|_se, _this, _pratt, _left| { unreachable!(); }),
+                                                     extra: TokenExtra::default(),
                                                  }
                                       }
                                   )
@@ -557,6 +352,7 @@ fn test_static() {
                                                      children: vec![],
                                                      fnud: Rc::new(|_se, this, _pratt| { this }),
                                                      fled: Rc::new(|_se, _this, _pratt, _left| { unreachable!(); }),
+                                                     extra: TokenExtra::default(),
                                                  }
                                       }
                                   )
@@ -577,6 +373,7 @@ fn test_static() {
                                                                        se.add_child(pratt.parse(0));
                                                                        this
                                                                    }),
+                                                     extra: TokenExtra::default(),
                                                  }
                                       }
                                   )
@@ -598,6 +395,7 @@ fn test_static() {
                                                                        se.add_child(pratt.parse(0));
                                                                        this
                                                                    }),
+                                                     extra: TokenExtra::default(),
                                                  }
                                       }
                                   )
@@ -641,3 +439,1326 @@ fn test_static() {
     #[cfg(feature="gc3c")]
     gc::finalize();
 }
+
+
+fn dynsym(code: &str, lbp: u8, fnud: Fnud, fled: Fled) -> PrattBox<DynamicSymbol> {
+    prattbox!(
+        DynamicSymbol {
+            token: DynamicToken {
+                       code: String::from(code),
+                       lbp,
+                       children: vec![],
+                       fnud,
+                       fled,
+                       extra: TokenExtra::default(),
+                   }
+        }
+    )
+}
+
+//  nud of tokens that cannot start an operand
+fn no_nud() -> Fnud {
+    Rc::new(|_se, _this, _pratt| { unreachable!(); })
+}
+
+fn no_led() -> Fled {
+    Rc::new(|_se, _this, _pratt, _left| { unreachable!(); })
+}
+
+//  led of a left associative operator of binding power 'lbp'
+fn binary(lbp: u8) -> Fled {
+    Rc::new(move |se, this, pratt, left| {
+        se.add_child(left);
+        se.add_child(pratt.parse(lbp));
+        this
+    })
+}
+
+fn dynamic_tokenizer(program: &str) -> StringTokenizer<DynamicSymbol> {
+    let tokenizer = StringTokenizer::new(String::from(program));
+    tokenizer.register_token("end", Box::new(|s| dynsym(s, 0, no_nud(), no_led())));
+    for &typ in ["string", "literal", "num"].iter() {
+        tokenizer.register_token(typ, Box::new(|s| dynsym(s, 0, Rc::new(|_se, this, _pratt| { this }), no_led())));
+    }
+    tokenizer.register_token("+", Box::new(|s| dynsym(s, 20, no_nud(), binary(20))));
+    tokenizer.register_token("*", Box::new(|s| dynsym(s, 30, no_nud(), binary(30))));
+    tokenizer
+}
+
+//  the tail of every test: the collector gets back what the test left
+fn finish() {
+    #[cfg(feature="gc3c")]
+    gc::finalize();
+}
+
+
+#[test]
+fn test_cancel_before_parse() {
+    let parser = Pratt::new(Box::new(dynamic_tokenizer("1 + 2 * 3 .")));
+    parser.cancel_handle().cancel();
+    assert_eq!(parser.try_pparse().err(), Some(ParseError::Cancelled));
+    // nothing was parsed: pparse() gives the empty stand-in, error() tells why
+    assert_eq!(show(&parser.pparse()), "");
+    assert_eq!(parser.error(), Some(ParseError::Cancelled));
+    // the parser starts over once the handle is reset
+    parser.cancel_handle().reset();
+    assert_eq!(parser.error(), None);
+    assert_eq!(show(&parser.try_pparse().unwrap()), "(+ 1 (* 2 3))");
+    finish();
+}
+
+#[test]
+fn test_cancel_during_parse() {
+    let program = "1 + 2 * 3 + 4 .";
+    let tokenizer = dynamic_tokenizer(program);
+    let handle: Rc<RefCell<Option<CancelHandle>>> = Rc::new(RefCell::new(None));
+    let h = handle.clone();
+    // the user "keeps typing" while the parser is inside the led of '*'
+    tokenizer.register_token("*", Box::new(move |s| {
+        let h = h.clone();
+        dynsym(s, 30, no_nud(), Rc::new(move |se, this, pratt, left| {
+            h.borrow().as_ref().unwrap().cancel();
+            binary(30)(se, this, pratt, left)
+        }))
+    }));
+    let parser = Pratt::new(Box::new(tokenizer));
+    *handle.borrow_mut() = Some(parser.cancel_handle());
+    let ast = parser.pparse();
+    assert_eq!(parser.error(), Some(ParseError::Cancelled));
+    assert_eq!(parser.current().unwrap().borrow_mut().token.code, "3");
+    // the abandoned partial tree is still well formed
+    assert_eq!(show(&ast), "(+ 1 (* 2 3))");
+    finish();
+}
+
+
+#[derive(Clone)]
+struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+impl std::io::Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_pretty_trace() {
+    let buf = SharedBuf(Rc::new(RefCell::new(Vec::new())));
+    let mut parser = Pratt::new(Box::new(dynamic_tokenizer("1 * 2 + 3 .")));
+    parser.set_tracer(Rc::new(PrettyTracer::new(buf.clone())));
+    let ast = parser.pparse();
+    assert_eq!(format!("{}", *ast.borrow_mut()), "(+ (* 1 2) 3)");
+    let trace = String::from_utf8(buf.0.borrow().clone()).unwrap();
+    assert_eq!(trace, "\
+advance 1
+advance *
+nud 1
+-> 1
+rbp 0 < lbp 30 of *: led
+advance 2
+led * left 1
+  advance +
+  nud 2
+  -> 2
+  rbp 30 >= lbp 20 of +: stop
+-> (* 1 2)
+rbp 0 < lbp 20 of +: led
+advance 3
+led + left (* 1 2)
+  advance .
+  nud 3
+  -> 3
+  rbp 20 >= lbp 0 of .: stop
+-> (+ (* 1 2) 3)
+rbp 0 >= lbp 0 of .: stop
+");
+    finish();
+}
+
+
+#[test]
+fn test_replay_session() {
+    let recorder = Rc::new(Recorder::new());
+    let mut parser = Pratt::new(Box::new(dynamic_tokenizer("1 * 2 + 3 .")));
+    parser.set_tracer(recorder.clone());
+    parser.pparse();
+    let session = recorder.session();
+    assert_eq!(session.steps.len(), 21);
+    let stop = &session.steps[10];
+    assert_eq!((stop.rbp, stop.lbp), (Some(30), Some(20)));
+    assert_eq!(stop.token, "+");
+    assert_eq!(stop.action, "stop before +");
+    // the whole partial tree, with the pending led
+    assert_eq!(stop.tree, "(* 1 2)");
+    assert_eq!(session.steps.last().unwrap().tree, "(+ (* 1 2) 3)");
+
+    let text = session.to_text();
+    let mut lines = text.lines();
+    assert_eq!(lines.next().unwrap(), "| step | token | rbp | lbp | action          | tree          |");
+    assert_eq!(text.lines().nth(12).unwrap(), "| 11   | +     | 30  | 20  |   stop before + | (* 1 2)       |");
+
+    let html = session.to_html();
+    assert!(html.starts_with("<!DOCTYPE html>"));
+    assert_eq!(html.matches("<tr class=\"step\">").count(), 21);
+    assert!(html.contains("<td>(+ (* 1 2) 3)</td>"));
+
+    // inside the led of '*', the led of '+' is still pending around it
+    let recorder = Rc::new(Recorder::new());
+    let mut parser = Pratt::new(Box::new(dynamic_tokenizer("1 + 2 * 3 .")));
+    parser.set_tracer(recorder.clone());
+    parser.pparse();
+    let session = recorder.session();
+    let stop = session.steps.iter().find(|s| s.action == "stop before .").unwrap();
+    assert_eq!((stop.depth, stop.tree.as_str()), (2, "(+ 1 (* 2 3))"));
+    finish();
+}
+
+
+#[test]
+fn test_single_char_tokens() {
+    // the char right after a single char token is not skipped
+    let grammar = Grammar::arithmetic();
+    let ast = grammar.parser("(1 + 2)*3 .").try_pparse().unwrap();
+    assert_eq!(show(&ast), "(* (+ 1 2) 3)");
+    let ast = grammar.parser("-(x) .").try_pparse().unwrap();
+    assert_eq!(show(&ast), "(- x)");
+    finish();
+}
+
+#[test]
+fn test_number_end() {
+    // any char that cannot go on with a number ends it
+    let ast = Grammar::arithmetic().parser("2*x .").try_pparse().unwrap();
+    assert_eq!(show(&ast), "(* 2 x)");
+    finish();
+}
+
+
+fn show(ast: &PrattBox<DynamicSymbol>) -> String {
+    format!("{}", *ast.borrow_mut())
+}
+
+#[test]
+fn test_grammar_parse() {
+    let grammar = Grammar::arithmetic();
+    assert_eq!(grammar.check(), vec![]);
+    let parser = grammar.parser("- ( 1 + 2 ) * 3 ^ 2 ^ x - 4 .");
+    let ast = parser.try_pparse().unwrap();
+    assert_eq!(show(&ast), "(- (* (- (+ 1 2)) (^ 3 (^ 2 x))) 4)");
+    finish();
+}
+
+#[test]
+fn test_grammar_syntax_error() {
+    let parser = Grammar::arithmetic().parser("( 1 + 2 * 3 .");
+    assert_eq!(parser.try_pparse().err(),
+               Some(ParseError::Syntax(String::from("expected ) found ."))));
+    finish();
+}
+
+#[test]
+fn test_grammar_check() {
+    let mut g = Grammar::new();
+    g.atom("num");
+    g.infix("+", 20, Assoc::Left);
+    g.infix_rbp("*", 30, 0);
+    g.infix("+", 20, Assoc::Right);
+    g.register("!", 50, Some(Prefix::Operator { rbp: 50 }), None);
+    g.register("?", 10, None, None);
+    let issues = g.check();
+    assert_eq!(issues, vec![
+        Issue::Duplicate { name: "+", role: "led" },
+        Issue::MissingEnd,
+        Issue::Associativity { name: "*", lbp: 30, rbp: 0 },
+        Issue::PrefixWithLbp { name: "!", lbp: 50 },
+        Issue::MissingLed { name: "?", lbp: 10 },
+    ]);
+    assert_eq!(format!("{}", issues[2]),
+               "* parses its right operand with rbp 0 against lbp 30: neither left (30) nor right (29) associative");
+
+    // close tokens of groups are no duplicates, the end token stays one
+    let mut g = Grammar::new();
+    g.end(";");
+    g.group("(", ")");
+    g.register(")", 0, None, None);
+    g.register(";", 0, None, None);
+    g.atom("num");
+    g.infix_rbp("@", 40, 255);
+    assert_eq!(g.check(), vec![Issue::Associativity { name: "@", lbp: 40, rbp: 255 }]);
+}
+
+
+#[test]
+fn test_precedence_table() {
+    let mut grammar = Grammar::arithmetic();
+    grammar.postfix("!", 60);
+    grammar.nud("@", Rc::new(|_se, this, _pratt| this));
+    let table = PrecedenceTable::new(&grammar);
+    assert_eq!(table.to_markdown(), "\
+| Level | Operator | Fixity | Associativity | Binding power |
+|---|---|---|---|---|
+| 1 | `(` `)` | group |  |  |
+| 2 | `!` | postfix |  | 60 |
+| 3 | `-` | prefix |  | 50 |
+| 4 | `^` | infix | right | 40 |
+| 5 | `*` | infix | left | 30 |
+| 5 | `/` | infix | left | 30 |
+| 6 | `+` | infix | left | 20 |
+| 6 | `-` | infix | left | 20 |
+| 7 | `@` | prefix |  |  |
+");
+    let html = table.to_html();
+    assert!(html.contains("<tr><td>3</td><td><code>-</code></td><td>prefix</td><td></td><td>50</td></tr>"));
+}
+
+
+fn language() -> Grammar {
+    let mut g = Grammar::arithmetic();
+    g.call("(", ",", ")", 60);
+    g.infix_mixfix("?", 10, &[":"], 9);
+    g.prefix_mixfix("if", &["then", "else"], 5);
+    g
+}
+
+#[test]
+fn test_mixfix_and_call() {
+    let grammar = language();
+    assert_eq!(grammar.check(), vec![]);
+    let ast = grammar.parser("if f ( x , 1 + 2 ) then g ( ) else a ? b : c ? d : e .").try_pparse().unwrap();
+    assert_eq!(show(&ast), "(if (( f x (+ 1 2)) (( g) (? a b (? c d e)))");
+    finish();
+}
+
+#[test]
+fn test_railroad() {
+    let grammar = language();
+    let diagrams = railroad::diagrams(&grammar);
+    let names: Vec<&str> = diagrams.iter().map(|d| d.name.as_str()).collect();
+    assert_eq!(names, vec!["infix +", "prefix -", "infix -", "infix *", "infix /", "infix ^",
+                           "prefix (", "infix (", "infix ?", "prefix if"]);
+    let call = &diagrams[7];
+    match call.node {
+        Node::Sequence(ref nodes) => assert_eq!(nodes[2],
+            Node::Optional(Box::new(Node::Repeat(Box::new(Node::NonTerminal(String::from("expr"))),
+                                                 Some(Box::new(Node::Terminal(String::from(",")))))))),
+        _ => panic!("call is not a sequence"),
+    }
+    let svg = call.to_svg();
+    assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\""));
+    assert!(svg.ends_with("</svg>\n"));
+    assert_eq!(svg.matches("<rect class=\"terminal\"").count(), 3);
+    assert_eq!(svg.matches("<rect class=\"nonterminal\"").count(), 2);
+    let all = railroad::expression(&grammar).to_svg();
+    assert_eq!(all.matches("<rect").count(), 3 + 3 * 5 + 2 + 3 + 5 + 5 + 6);
+}
+
+
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (self.0 >> 33) % n
+    }
+}
+
+fn node(grammar: &Grammar, code: &str, children: Vec<PrattBox<DynamicSymbol>>) -> PrattBox<DynamicSymbol> {
+    let key = match grammar.rule(code) {
+        Some(_) => code,
+        None => "literal",
+    };
+    let n = grammar.rule(key).unwrap().token(code);
+    for c in children {
+        n.borrow_mut().token.add_child(c);
+    }
+    n
+}
+
+fn random_tree(grammar: &Grammar, rng: &mut Rng, depth: u32) -> PrattBox<DynamicSymbol> {
+    let pick = if depth == 0 { 0 } else { rng.below(9) };
+    let sub = |rng: &mut Rng| random_tree(grammar, rng, depth - 1);
+    match pick {
+        0 => {
+            let atoms = ["a", "b", "x", "1", "2", "42"];
+            node(grammar, atoms[rng.below(atoms.len() as u64) as usize], vec![])
+        }
+        1 => node(grammar, "-", vec![sub(rng)]),
+        2 => node(grammar, "!", vec![sub(rng)]),
+        3 => {
+            let mut args = vec![sub(rng)];
+            for _ in 0..rng.below(3) {
+                args.push(sub(rng));
+            }
+            node(grammar, "(", args)
+        }
+        4 => node(grammar, "?", vec![sub(rng), sub(rng), sub(rng)]),
+        5 => node(grammar, "if", vec![sub(rng), sub(rng), sub(rng)]),
+        _ => {
+            let ops = ["+", "-", "*", "/", "^"];
+            let op = ops[rng.below(ops.len() as u64) as usize];
+            node(grammar, op, vec![sub(rng), sub(rng)])
+        }
+    }
+}
+
+#[test]
+fn test_print_minimal_parens() {
+    let mut grammar = language();
+    grammar.postfix("!", 60);
+    let parse = |src: &str| grammar.parser(&format!("{} .", src)).try_pparse().unwrap();
+    for &(src, printed) in [
+        ("( 1 + 2 ) * 3", "(1 + 2) * 3"),
+        ("1 + ( 2 * 3 )", "1 + 2 * 3"),
+        ("( a - b ) - c", "a - b - c"),
+        ("a - ( b - c )", "a - (b - c)"),
+        ("( a ^ b ) ^ c", "(a ^ b) ^ c"),
+        ("a ^ ( b ^ c )", "a ^ b ^ c"),
+        ("- ( a ^ b )", "- (a ^ b)"),
+        ("( - a ) ^ b", "- a ^ b"),
+        ("( - f ) ( x , y )", "(- f)(x , y)"),
+        ("( a ? b : c ) ? d : ( e + 1 )", "(a ? b : c) ? d : e + 1"),
+        ("( if a then b else c ) + 1", "(if a then b else c) + 1"),
+        ("f ( ) !", "f() !"),
+    ].iter() {
+        assert_eq!(printer::print(&grammar, &parse(src)), printed);
+    }
+    finish();
+}
+
+#[test]
+fn test_print_parse_roundtrip() {
+    let mut grammar = language();
+    grammar.postfix("!", 60);
+    let mut rng = Rng(7);
+    for _ in 0..500 {
+        let tree = random_tree(&grammar, &mut rng, 4);
+        let printed = printer::print(&grammar, &tree);
+        let reparsed = grammar.parser(&format!("{} .", printed)).try_pparse().unwrap();
+        assert_eq!(show(&reparsed), show(&tree), "printed as {}", printed);
+        let formatted = printer::format(&grammar, &tree, 12);
+        let reparsed = grammar.parser(&format!("{} .", formatted)).try_pparse().unwrap();
+        assert_eq!(show(&reparsed), show(&tree), "formatted as {}", formatted);
+    }
+    finish();
+}
+
+
+#[test]
+fn test_format_width() {
+    let grammar = language();
+    let src = "if total ( first , second * 2 , third ) then alpha + beta - gamma + delta * epsilon else fallback ( ) .";
+    let ast = grammar.parser(src).try_pparse().unwrap();
+    assert_eq!(printer::format(&grammar, &ast, 200),
+               "if total(first , second * 2 , third) then alpha + beta - gamma + delta * epsilon else fallback()");
+    assert_eq!(printer::format(&grammar, &ast, 50), "\
+if total(first , second * 2 , third)
+    then alpha + beta - gamma + delta * epsilon
+    else fallback()");
+    assert_eq!(printer::format(&grammar, &ast, 26), "\
+if total(
+        first ,
+        second * 2 ,
+        third
+    )
+    then alpha
+        + beta
+        - gamma
+        + delta * epsilon
+    else fallback()");
+    finish();
+}
+
+
+#[test]
+fn test_lossless_cst() {
+    let grammar = Grammar::arithmetic();
+    let src = "  # totals\n( 1 +   2 )  # sum\n  * x .\n# done\n";
+    let tokenizer = grammar.tokenizer(src);
+    tokenizer.set_line_comment("#");
+    let tree = cst::parse(tokenizer).unwrap();
+    assert_eq!(tree.text(), src);
+
+    let root: Vec<_> = tree.tokens().map(|t| t.kind.clone()).collect();
+    assert_eq!(root, vec!["end"]);
+    let expr = tree.nodes().next().unwrap();
+    match expr.ast() {
+        Ast::Infix { left, op, right } => {
+            assert_eq!(op.text, "*");
+            assert_eq!(op.leading, "  ");
+            assert_eq!(op.trailing, " ");
+            assert_eq!(left.kind, "(");
+            assert_eq!(left.text(), "  # totals\n( 1 +   2 )  # sum\n");
+            assert_eq!(right.ast(), Ast::Atom(right.tokens().next().unwrap()));
+            assert_eq!(right.tokens().next().unwrap().span, 34..35);
+        }
+        _ => panic!("expected an infix node"),
+    }
+    finish();
+}
+
+
+#[test]
+fn test_parse_events() {
+    let grammar = Grammar::arithmetic();
+    let stream = events::parse(grammar.parser("1 + 2 * 3 - 4 ."), Vec::new()).unwrap();
+    let shape: Vec<String> = stream.iter().map(|e| match *e {
+        Event::StartNode(ref kind) => format!("<{}", kind),
+        Event::Token(ref t) => t.text.clone(),
+        Event::FinishNode => String::from(">"),
+    }).collect();
+    assert_eq!(shape.join(" "), "<root <- <+ <num 1 > + <* <num 2 > * <num 3 > > > - <num 4 > > . >");
+
+    assert!(events::parse(grammar.parser("( 1 + 2 ."), ()).is_err());
+
+    let grammar = language();
+    for src in &["- ( 1 + 2 ) * 3 ^ 2 ^ x - 4 .",
+                 "if f ( x , 1 + 2 ) then g ( ) else a ? b : c ? d : e .",
+                 "( ( a ) ) ."] {
+        let expected = grammar.parser(src).try_pparse().unwrap();
+        let built = events::parse(grammar.parser(src), DynamicBuilder::new(&grammar)).unwrap().finish().unwrap();
+        assert_eq!(show(&built), show(&expected));
+    }
+
+    let src = "f ( a ,  b )   * 2 .\n";
+    let tokenizer = grammar.tokenizer(src);
+    tokenizer.set_lossless(true);
+    let green = events::parse(Pratt::new(Box::new(tokenizer)), GreenBuilder::new()).unwrap().finish().unwrap();
+    assert_eq!(green.text(), src);
+    assert_eq!(green.len(), src.len());
+    assert_eq!(green.children()[0].kind(), "*");
+    assert_eq!(green.children()[0].len(), 19);
+
+    // the events of an expression are sent once it is parsed, and without
+    // its tree the parser returns bare operators
+    struct Shared(Rc<RefCell<Vec<Event>>>);
+    impl Sink for Shared {
+        fn start_node(&mut self, kind: &str) {
+            self.0.borrow_mut().start_node(kind)
+        }
+        fn token(&mut self, lexeme: Lexeme) {
+            self.0.borrow_mut().token(lexeme)
+        }
+        fn finish_node(&mut self) {
+            self.0.borrow_mut().finish_node()
+        }
+        fn checkpoint(&self) -> usize {
+            self.0.borrow().checkpoint()
+        }
+        fn start_node_at(&mut self, checkpoint: usize, kind: &str) {
+            self.0.borrow_mut().start_node_at(checkpoint, kind)
+        }
+    }
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let tracer = Rc::new(EventTracer::new(Shared(seen.clone())));
+    let mut pratt = grammar.parser("1 + 2 . 3 * 4 .");
+    pratt.set_tracer(tracer.clone());
+    pratt.set_build_tree(false);
+    let mut expressions = pratt.expressions();
+    assert_eq!(show(&expressions.next().unwrap().unwrap()), "+");
+    let finished = |events: &[Event]| events.iter().filter(|&e| *e == Event::FinishNode).count();
+    assert_eq!(finished(&seen.borrow()), 3);
+    assert_eq!(show(&expressions.next().unwrap().unwrap()), "*");
+    assert_eq!(finished(&seen.borrow()), 6);
+    assert!(expressions.next().is_none());
+    tracer.finish();
+    assert_eq!(*seen.borrow(), events::parse_all(grammar.parser("1 + 2 . 3 * 4 ."), Vec::new()).unwrap());
+    finish();
+}
+
+
+#[test]
+fn test_green_red_tree() {
+    let grammar = Grammar::arithmetic();
+    let src = "( x * 2 ) + ( x * 2 ) - y .";
+    let green = green::parse(grammar.tokenizer(src)).unwrap();
+    let root = SyntaxNode::new_root(green.clone());
+    assert_eq!(root.text(), src);
+
+    // both "( x * 2 )" are the same green node
+    let minus = &root.children()[0];
+    let plus = &minus.children()[0];
+    let groups = plus.children();
+    assert_eq!(groups[0].kind(), "(");
+    assert!(Arc::ptr_eq(groups[0].green(), groups[1].green()));
+    assert_eq!(groups[0].text_range(), 0..10);
+    assert_eq!(groups[1].text_range(), 12..22);
+    assert!(groups[0] != groups[1]);
+
+    let x = groups[1].covering_node(14..15);
+    assert_eq!(x.kind(), "literal");
+    assert_eq!(x.tokens()[0].text_range(), 14..15);
+    let kinds: Vec<String> = x.ancestors().iter().map(|n| n.kind().to_string()).collect();
+    assert_eq!(kinds, vec!["*", "(", "+", "-", "root"]);
+
+    // editing shares everything off the path to the root
+    let y = minus.children()[1].clone();
+    let z = green::parse(grammar.tokenizer("z .")).unwrap();
+    let z = match z.children()[0] {
+        GreenElement::Node(ref n) => n.clone(),
+        _ => unreachable!(),
+    };
+    let edited = SyntaxNode::new_root(y.replace_with(z));
+    assert_eq!(edited.text(), "( x * 2 ) + ( x * 2 ) - z .");
+    assert!(Arc::ptr_eq(edited.children()[0].children()[0].green(), plus.green()));
+
+    // cursors can go to other threads
+    let handle = std::thread::spawn(move || root.children()[0].children().len());
+    assert_eq!(handle.join().unwrap(), 2);
+    finish();
+}
+
+
+//  random edits on texts of formatted random trees, each checked against a full parse
+#[test]
+fn test_incremental_reparse() {
+    let mut grammar = language();
+    grammar.postfix("!", 60);
+    // the edit, then the text after it, as a full parse sees them
+    let check = |doc: &mut Document, edit: &Edit| -> bool {
+        let text = edit.apply(doc.text());
+        let result = doc.edit(edit);
+        assert_eq!(doc.text(), text);
+        assert_eq!(doc.tree().text(), text);
+        match green::parse(grammar.tokenizer(&text)) {
+            Ok(full) => {
+                assert!(result.is_ok() && doc.error().is_none(), "{:?} on {:?}", edit, text);
+                assert!(*doc.green() == full, "{:?} on {:?}", edit, text);
+                result.unwrap().len() < text.len()
+            }
+            Err(err) => {
+                assert_eq!(result, Err(err.clone()), "{:?} on {:?}", edit, text);
+                assert_eq!(doc.error(), Some(&err));
+                false
+            }
+        }
+    };
+    let mut doc = Document::new(&grammar, "a .\nb .\nc! \n").unwrap();
+    check(&mut doc, &Edit::new(12..12, "1.5"));
+    let mut doc = Document::new(&grammar, "a .\nb .\nc \n\n( q )").unwrap();
+    check(&mut doc, &Edit::new(12..12, "( q )"));
+
+    let pieces = ["1", "x", "1.5", "!", "(", ")", "( q )", "a + b", "2 * ( 3 - z )", ".", " . ", "\n", "\n  q", " ", "f ( )", "-", ","];
+    let mut rng = Rng(11);
+    let mut partial = 0;
+    let mut failed = 0;
+    for _ in 0..100 {
+        let mut text = String::new();
+        for _ in 0..1 + rng.below(3) {
+            let tree = random_tree(&grammar, &mut rng, 4);
+            text.push_str(&format!("{} .\n", printer::format(&grammar, &tree, 12)));
+        }
+        let mut doc = Document::new(&grammar, &text).unwrap();
+        for _ in 0..8 {
+            let len = doc.text().len() as u64;
+            let start = rng.below(len + 1);
+            let end = start + rng.below((len - start).min(4) + 1);
+            let mut insert = String::new();
+            for _ in 0..rng.below(3) {
+                insert.push_str(pieces[rng.below(pieces.len() as u64) as usize]);
+            }
+            let (start, end) = (start as usize, end as usize);
+            let removed = String::from(&doc.text()[start..end]);
+            if check(&mut doc, &Edit::new(start..end, &insert)) {
+                partial += 1;
+            }
+            // undone, the error goes with it
+            if doc.error().is_some() {
+                failed += 1;
+                check(&mut doc, &Edit::new(start..start + insert.len(), &removed));
+                assert!(doc.error().is_none());
+            }
+        }
+    }
+    assert!(partial > 150 && failed > 200, "{} partial reparses, {} errors", partial, failed);
+
+    // an edit outside any group reparses the expression it is in
+    let mut doc = Document::new(&grammar, "1 + 2 .\nx * y .\n3 .\n").unwrap();
+    let first = doc.tree().children()[0].green().clone();
+    assert_eq!(doc.edit(&Edit::new(12..13, "( z )")), Ok(8..20));
+    assert_eq!(doc.text(), "1 + 2 .\nx * ( z ) .\n3 .\n");
+    assert!(Arc::ptr_eq(doc.tree().children()[0].green(), &first));
+    // and splits it when it puts in an end token
+    assert_eq!(doc.edit(&Edit::new(9..9, " . y")), Ok(8..24));
+    assert_eq!(doc.tree().children().len(), 4);
+    // as does any end token of the grammar
+    let mut ends = language();
+    ends.end(";");
+    let mut doc = Document::new(&ends, "1 + 2 ;\nx * y ;\n").unwrap();
+    assert_eq!(doc.edit(&Edit::new(12..13, "z")), Ok(8..16));
+
+    // the cache keeps only what a tree still uses
+    let builder = GreenBuilder::with_cache(NodeCache::new());
+    let (root, mut cache) = events::parse(grammar.parser("f ( x ) + 1 ."), builder).unwrap().finish_with_cache();
+    assert!(!cache.is_empty());
+    drop(root);
+    cache.evict();
+    assert!(cache.is_empty());
+    finish();
+}
+
+
+#[test]
+fn test_relex() {
+    let grammar = language();
+    let lex = |text: &str| {
+        let tokenizer = grammar.tokenizer(text);
+        tokenizer.set_lossless(true);
+        tokenizer
+    };
+    let text = "f ( \"a b\" , 'q(x y) , 12 ) + g ( 3 ) * h .\n";
+    let old = lex(text).lex();
+    assert_eq!(old.len(), 16);
+    assert_eq!(old[2].0.text, "\"a b\"");
+    assert_eq!(old[4].0.text, "'q(x y)");
+
+    let edits = vec![
+        Edit::new(8..8, "c"),           // inside the string
+        Edit::new(16..16, " z"),        // inside the quote's parentheses
+        Edit::new(24..26, "123"),       // a number
+        Edit::new(4..4, "\""),         // a string that runs over the rest
+        Edit::new(36..37, "*"),         // a single char token
+        Edit::new(0..0, "  "),          // leading trivia
+        Edit::new(text.len() - 1..text.len(), " "),
+    ];
+    for edit in &edits {
+        let new = edit.apply(text);
+        let tokenizer = lex(&new);
+        let made = Rc::new(std::cell::Cell::new(0));
+        for kind in &["string", "num", "literal", "*", "+", "(", ")"] {
+            let made = made.clone();
+            let rule = grammar.rule(kind).unwrap().clone();
+            tokenizer.register_token(kind, Box::new(move |s| { made.set(made.get() + 1); rule.token(s) }));
+        }
+        let relexed = tokenizer.relex(&old, edit);
+        let full = lex(&new).lex();
+        assert!(relexed == full, "{:?}", edit);
+        if edit.insert != "\"" {
+            let text: String = relexed.iter().map(|(l, _)| l.full_text()).collect();
+            assert_eq!(text, new);
+        }
+        if edit.range.start > 0 && edit.insert != "\"" {
+            assert!(made.get() < 4, "{:?} relexed {} tokens", edit, made.get());
+        }
+    }
+
+    // random edits against lexing the whole text again, comments included
+    let lex = |text: &str| {
+        let tokenizer = lex(text);
+        tokenizer.set_line_comment("#");
+        tokenizer
+    };
+    let text = "f ( \"a b\" , 'q(x y) , 12 ) + g ( 3 ) * h - -1.5 .\n# c\nx ! .\n";
+    let edit = Edit::new(52..55, "! ");
+    assert!(lex(&edit.apply(text)).relex(&lex(text).lex(), &edit) == lex(&edit.apply(text)).lex());
+    let pieces = ["f", "(", ")", "\"a b\"", "'q(x y)", ",", "12", "-1.5", "+", "-", "!", ".", "# c", "\n", " ", "  ", "\""];
+    let mut rng = Rng(38);
+    for _ in 0..300 {
+        let mut text = String::new();
+        for _ in 0..rng.below(30) {
+            text.push_str(pieces[rng.below(pieces.len() as u64) as usize]);
+            if rng.below(3) > 0 {
+                text.push(' ');
+            }
+        }
+        let old = lex(&text).lex();
+        for _ in 0..20 {
+            let start = rng.below(text.len() as u64 + 1) as usize;
+            let end = start + rng.below((text.len() - start) as u64 + 1).min(4) as usize;
+            let insert = match rng.below(3) {
+                0 => "",
+                _ => pieces[rng.below(pieces.len() as u64) as usize],
+            };
+            let edit = Edit::new(start..end, insert);
+            let new = edit.apply(&text);
+            assert!(lex(&new).relex(&old, &edit) == lex(&new).lex(), "{:?} on {:?}", edit, text);
+        }
+    }
+    finish();
+}
+
+
+//  an endless "( x1 + 23 ) * y ." ... without a String behind it
+struct Generated {
+    line: Vec<u8>,
+    pos: usize,
+    left: usize,
+}
+
+impl std::io::Read for Generated {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut n = 0;
+        while n < buf.len() && self.left > 0 {
+            buf[n] = self.line[self.pos];
+            self.pos = (self.pos + 1) % self.line.len();
+            self.left -= 1;
+            n += 1;
+        }
+        Ok(n)
+    }
+}
+
+#[test]
+fn test_streaming_tokenizer() {
+    use std::io::{BufReader, Cursor};
+    let grammar = language();
+    let text = "# sum\nf ( \"a b\" , 'q(x y) ) + 12.5 * x  # tail\n.\n";
+    let whole = grammar.tokenizer(text);
+    whole.set_lossless(true);
+    whole.set_line_comment("#");
+    // tokens and comments cut by two byte chunks
+    let stream = StringTokenizer::from_reader(BufReader::with_capacity(2, Cursor::new(text.as_bytes().to_vec())));
+    grammar.install(&stream);
+    stream.set_lossless(true);
+    stream.set_line_comment("#");
+    let lexemes = |lexed: Vec<(Lexeme, _)>| lexed.into_iter().map(|(l, _)| l).collect::<Vec<_>>();
+    assert_eq!(lexemes(stream.lex()), lexemes(whole.lex()));
+
+    let stream = StringTokenizer::from_reader(BufReader::with_capacity(1, Cursor::new(b"1 + 2 * 3 .".to_vec())));
+    grammar.install(&stream);
+    let ast = Pratt::new(Box::new(stream)).try_pparse().unwrap();
+    assert_eq!(show(&ast), "(+ 1 (* 2 3))");
+
+    let line = "( x1 + 23 ) * y .\n";
+    let lines = 20000;
+    let generated = Generated { line: line.as_bytes().to_vec(), pos: 0, left: line.len() * lines };
+    let stream = StringTokenizer::from_reader(BufReader::new(generated));
+    grammar.install(&stream);
+    let mut count = 0;
+    let mut last = Lexeme::default();
+    for (lexeme, _) in stream.lex() {
+        count += 1;
+        last = lexeme;
+    }
+    assert_eq!(count, 8 * lines);
+    assert_eq!(last.text, ".");
+    assert_eq!(last.span.end, line.len() * lines - 1);
+    assert!(stream.io_error().is_none());
+    finish();
+}
+
+#[test]
+fn test_utf8() {
+    use std::io::{BufReader, Cursor};
+    let mut grammar = Grammar::arithmetic();
+    grammar.infix("×", 30, Assoc::Left);
+    let parse = |text: &str| show(&grammar.parser(text).try_pparse().unwrap());
+    assert_eq!(parse("é + 1 ."), "(+ é 1)");
+    assert_eq!(parse("2×3 + übel ."), "(+ (× 2 3) übel)");
+    let lexed = grammar.tokenizer("\"héllo\" + 'wörld .").lex();
+    let texts: Vec<_> = lexed.iter().map(|(l, _)| l.text.as_str()).collect();
+    assert_eq!(texts, vec!["\"héllo\"", "+", "'wörld", "."]);
+    // chars split between one byte chunks
+    for text in &["ü + 1 .", "\"héllo\" × x2 ."] {
+        let stream = StringTokenizer::from_reader(BufReader::with_capacity(1, Cursor::new(text.as_bytes().to_vec())));
+        grammar.install(&stream);
+        let whole = grammar.tokenizer(text);
+        let lexemes = |lexed: Vec<(Lexeme, _)>| lexed.into_iter().map(|(l, _)| l).collect::<Vec<_>>();
+        assert_eq!(lexemes(stream.lex()), lexemes(whole.lex()));
+    }
+    let stream = StringTokenizer::from_reader(BufReader::with_capacity(1, Cursor::new("ü + 1 .".as_bytes().to_vec())));
+    grammar.install(&stream);
+    assert_eq!(show(&Pratt::new(stream).try_pparse().unwrap()), "(+ ü 1)");
+    finish();
+}
+
+#[test]
+fn test_expressions() {
+    let grammar = language();
+    let pratt = grammar.parser("1 + 2 .\n( 3 * .\nf ( x ) 4 .\n-5 * 6 .\n7");
+    let results: Vec<_> = pratt.expressions().map(|r| r.map(|ast| show(&ast))).collect();
+    assert_eq!(results.len(), 5);
+    assert_eq!(results[0], Ok(String::from("(+ 1 2)")));
+    assert!(results[1].is_err());
+    assert_eq!(results[2], Err(ParseError::Syntax(String::from("expected end of expression, found 4"))));
+    assert_eq!(results[3], Ok(String::from("(* -5 6)")));
+    // the last expression may end with the input
+    assert_eq!(results[4], Ok(String::from("7")));
+
+    assert_eq!(grammar.parser("").expressions().count(), 0);
+    assert_eq!(grammar.parser(" . ").expressions().count(), 1);
+
+    // an operand missing at the end of input
+    let eof = Err(ParseError::Syntax(String::from("unexpected end of input")));
+    assert_eq!(grammar.parser("").try_pparse().map(|ast| show(&ast)), eof);
+    assert_eq!(grammar.parser("1 +").try_pparse().map(|ast| show(&ast)), eof);
+    let results: Vec<_> = grammar.parser("1 + 2 .\n3 +").expressions().map(|r| r.map(|ast| show(&ast))).collect();
+    assert_eq!(results, vec![Ok(String::from("(+ 1 2)")), eof.clone()]);
+    // the nud or led still running on the last token does not see it again
+    for src in ["(", "f (", "a ?"].iter() {
+        let results: Vec<_> = grammar.parser(src).expressions().map(|r| r.map(|ast| show(&ast))).collect();
+        assert_eq!(results, vec![eof.clone()], "{}", src);
+    }
+
+    // any token the grammar gives end() ends expressions
+    let mut g = language();
+    g.end(";");
+    let results: Vec<_> = g.parser("1 + 2 ; 3 + ; 4 * 5 ;").expressions().map(|r| r.map(|ast| show(&ast))).collect();
+    assert_eq!(results, vec![Ok(String::from("(+ 1 2)")), Err(ParseError::Syntax(String::from("unexpected ;"))),
+                             Ok(String::from("(* 4 5)"))]);
+    finish();
+}
+
+#[test]
+fn test_static_tokenizer() {
+    let grammar = language();
+    // the tokenizer type is known, it comes back after the parse
+    let parser: Pratt<DynamicSymbol, StringTokenizer<DynamicSymbol>> = grammar.parser("1 + f ( 2 ) * 3 .\n4 .");
+    let ast = parser.try_pparse().unwrap();
+    assert_eq!(show(&ast), "(+ 1 (* (( f 2) 3))");
+    let tokenizer = parser.into_tokenizer();
+    tokenizer.advance();
+    assert_eq!(show(&tokenizer.current().unwrap()), "4");
+
+    let boxed: BoxedPratt<DynamicSymbol> = Pratt::new(Box::new(grammar.tokenizer("( 1 + 2 ) * 3 .")));
+    assert_eq!(show(&boxed.try_pparse().unwrap()), "(* (+ 1 2) 3)");
+    finish();
+}
+
+//  a symbol that overrides nud and led: they get the parser with its
+//  tokenizer type and evaluate as they go, no tree is kept
+enum Calc {
+    Num(i64),
+    Op(char, u8),
+    End,
+}
+
+impl Token<Calc> for Calc {
+    fn lbp(&self) -> u8 {
+        match *self {
+            Calc::Op(_, lbp) => lbp,
+            _ => 0,
+        }
+    }
+}
+
+thread_local! {
+    static CALC_TOKENIZER: RefCell<Option<&'static str>> = const { RefCell::new(None) };
+}
+
+impl Symbol for Calc {
+    fn token(&mut self) -> &mut dyn Token<Calc> {
+        self
+    }
+    fn nud<T: ?Sized + AsPratt<Calc>>(&mut self, this: PrattBox<Calc>, _pratt: &Pratt<Calc, T>) -> PrattBox<Calc> {
+        this
+    }
+    fn led<T: ?Sized + AsPratt<Calc>>(&mut self, this: PrattBox<Calc>, pratt: &Pratt<Calc, T>, left: PrattBox<Calc>) -> PrattBox<Calc> {
+        CALC_TOKENIZER.with(|t| *t.borrow_mut() = Some(std::any::type_name::<T>()));
+        let (op, lbp) = match *self {
+            Calc::Op(op, lbp) => (op, lbp),
+            _ => unreachable!(),
+        };
+        let value = |node: PrattBox<Calc>| match *node.borrow_mut() {
+            Calc::Num(n) => n,
+            _ => unreachable!(),
+        };
+        let (a, b) = (value(left), value(pratt.parse(lbp)));
+        *self = Calc::Num(if op == '+' { a + b } else { a * b });
+        this
+    }
+}
+
+#[cfg(feature="gc3c")]
+impl Mark for Calc {
+    fn mark(&self, _gc: &mut InGcEnv) {}
+}
+
+#[test]
+fn test_static_dispatch() {
+    let tokenizer = StringTokenizer::new(String::from("1 + 2 * 3 + 4 ."));
+    tokenizer.register_token("num", Box::new(|s| prattbox!(Calc::Num(s.parse().unwrap()))));
+    tokenizer.register_token("+", Box::new(|_s| prattbox!(Calc::Op('+', 20))));
+    tokenizer.register_token("*", Box::new(|_s| prattbox!(Calc::Op('*', 30))));
+    tokenizer.register_token("end", Box::new(|_s| prattbox!(Calc::End)));
+    let result = Pratt::new(tokenizer).try_pparse().unwrap();
+    match *result.borrow_mut() {
+        Calc::Num(n) => assert_eq!(n, 11),
+        _ => panic!("not evaluated"),
+    }
+    // the led got a Pratt<Calc, StringTokenizer<Calc>>, not a Pratt<Calc>
+    let tokenizer = CALC_TOKENIZER.with(|t| t.borrow_mut().take()).unwrap();
+    assert!(tokenizer.contains("StringTokenizer"), "{}", tokenizer);
+    finish();
+}
+
+#[test]
+fn test_peek() {
+    use std::io::{BufReader, Cursor};
+    let grammar = language();
+    let code = |t: Option<PrattBox<DynamicSymbol>>| t.map(|t| show(&t));
+    let tokenizer = grammar.tokenizer("( a , b ) => a .");
+    assert_eq!(code(tokenizer.peek(2)), Some(String::from("a")));
+    tokenizer.advance();
+    assert_eq!(code(tokenizer.current()), Some(String::from("(")));
+    assert_eq!(code(tokenizer.peek(5)), Some(String::from("=>")));
+    assert_eq!(code(tokenizer.peek(7)), Some(String::from(".")));
+    assert_eq!(code(tokenizer.peek(8)), None);
+    tokenizer.advance();
+    assert_eq!(code(tokenizer.peek(0)), Some(String::from("a")));
+    assert_eq!(code(tokenizer.peek(4)), Some(String::from("=>")));
+
+    // lookahead from the input read so far, and beyond it
+    let stream = StringTokenizer::from_reader(BufReader::with_capacity(1, Cursor::new(b"x ? y : z .".to_vec())));
+    grammar.install(&stream);
+    let parser = Pratt::new(stream);
+    parser.advance();
+    assert_eq!(code(parser.peek(3)), Some(String::from(":")));
+    let ast = parser.parse(0);
+    assert_eq!(show(&ast), "(? x y z)");
+    assert_eq!(code(parser.current()), Some(String::from(".")));
+    finish();
+}
+
+#[test]
+fn test_try_parse() {
+    use pratt::grammar::{at, expect};
+    let mut g = language();
+    g.infix("=>", 1, Assoc::Right);
+    // "( a , b ) => body" is a lambda, else a group
+    g.nud("(", Rc::new(|se, this, pratt| {
+        let attempt = pratt.try_parse(|p| {
+            let mut params = vec![];
+            loop {
+                params.push(p.parse(100));
+                if !at(p, ",") {
+                    break;
+                }
+                p.advance();
+            }
+            expect(p, ")");
+            expect(p, "=>");
+            params.push(p.parse(1));
+            params
+        });
+        match attempt {
+            Some(children) => {
+                for child in children {
+                    se.add_child(child);
+                }
+                this
+            }
+            None => {
+                let inner = pratt.parse(0);
+                expect(pratt, ")");
+                inner
+            }
+        }
+    }));
+    let parse = |text: &str| g.parser(text).try_pparse().map(|ast| show(&ast));
+    assert_eq!(parse("( a , b ) => a + b ."), Ok(String::from("(( a b (+ a b))")));
+    assert_eq!(parse("( a , b ) * 2 ."), Err(ParseError::Syntax(String::from("expected ) found ,"))));
+    assert_eq!(parse("( 1 + 2 ) * 3 ."), Ok(String::from("(* (+ 1 2) 3)")));
+    assert_eq!(parse("( ( x ) ) ."), Ok(String::from("x")));
+
+    let parser = g.parser("1 + 2 * 3 .");
+    parser.advance();
+    let checkpoint = parser.checkpoint().unwrap();
+    #[cfg(not(feature="gc3c"))]
+    let first = Rc::downgrade(&parser.current().unwrap());
+    assert_eq!(show(&parser.parse(0)), "(+ 1 (* 2 3))");
+    parser.rewind(checkpoint).unwrap();
+    // the nodes of the first parse are gone, the current token is new
+    #[cfg(not(feature="gc3c"))]
+    assert!(first.upgrade().is_none());
+    assert_eq!(show(&parser.parse(0)), "(+ 1 (* 2 3))");
+    finish();
+}
+
+#[test]
+fn test_lexer_modes() {
+    let mut g = language();
+    g.atom("regex");
+    // "/" after "~" starts a regex
+    g.led("~", 30, Rc::new(|se, this, pratt, left| {
+        pratt.push_mode("regex");
+        let right = pratt.parse(30);
+        pratt.pop_mode();
+        se.add_child(left);
+        se.add_child(right);
+        this
+    }));
+    let tokenizer = |text: &str| {
+        let tokenizer = g.tokenizer(text);
+        tokenizer.register_mode("regex", Box::new(|input| {
+            if !input.starts_with('/') {
+                return None;
+            }
+            input[1..].find('/').map(|end| (end + 2, "regex"))
+        }));
+        tokenizer
+    };
+    let parse = |text: &str| Pratt::new(tokenizer(text)).try_pparse().map(|ast| show(&ast));
+    assert_eq!(parse("x ~ /a b*/ / 2 ."), Ok(String::from("(/ (~ x /a b*/) 2)")));
+    assert_eq!(parse("x / 2 / y ."), Ok(String::from("(/ (/ x 2) y)")));
+
+    // the relexed token replaces the old one in the syntax tree
+    let text = "x ~ /a (b)/ + 1 .\n";
+    let tree = cst::parse(tokenizer(text)).unwrap();
+    assert_eq!(tree.text(), text);
+    finish();
+}
+
+#[test]
+fn test_string_interpolation() {
+    let mut g = language();
+    g.interpolation("${", "}");
+    let parse = |text: &str| g.parser(text).try_pparse().map(|ast| show(&ast));
+    assert_eq!(parse("\"total: ${a + b * 2} items\" ."),
+               Ok(String::from("(\"total: ${ (+ a (* b 2)) } items\")")));
+    assert_eq!(parse("\"${x}${ f ( y ) }\" + \"z\" ."),
+               Ok(String::from("(+ (\"${ x }${ (( f y) }\") \"z)")));
+    // nested strings with their own interpolations
+    assert_eq!(parse("\"a ${ \"b ${ x } c\" } d\" ."),
+               Ok(String::from("(\"a ${ (\"b ${ x } c\") } d\")")));
+    assert_eq!(parse("\"a ${ x y } b\" ."), Err(ParseError::Syntax(String::from("expected the rest of the string found y"))));
+    assert_eq!(parse("\"€ ${a}\" ."), Ok(String::from("(\"€ ${ a }\")")));
+    // brackets opened in an interpolation are closed in it
+    let mut braces = language();
+    braces.interpolation("${", "}");
+    braces.group("{", "}");
+    let ast = braces.parser("\"a ${ {b} * {c} } d\" .").try_pparse().map(|ast| show(&ast));
+    assert_eq!(ast, Ok(String::from("(\"a ${ (* b c) } d\")")));
+
+    let text = "\"sum ${ 1 + 2 } !\" .";
+    let tokenizer = g.tokenizer(text);
+    let lexed: Vec<_> = tokenizer.lex().into_iter().map(|(l, _)| (l.kind, l.span)).collect();
+    assert_eq!(lexed, vec![
+        (String::from("string_head"), 0..7),
+        (String::from("num"), 8..9),
+        (String::from("+"), 10..11),
+        (String::from("num"), 12..13),
+        (String::from("string_tail"), 14..18),
+        (String::from("end"), 19..20),
+    ]);
+    finish();
+}
+
+#[test]
+fn test_layout() {
+    use pratt::grammar::{at, expect};
+    let mut g = language();
+    g.layout();
+    let kinds = |text: &str| {
+        let tokenizer = g.tokenizer(text);
+        tokenizer.set_line_comment("#");
+        let kinds: Vec<String> = tokenizer.lex().into_iter().map(|(l, _)| match l.kind.as_str() {
+            "newline" | "indent" | "dedent" => l.kind,
+            _ => l.text,
+        }).collect();
+        (kinds.join(" "), tokenizer.layout_error())
+    };
+    let text = "a\n  b + f (\nc ,\n      d )\n\n  # comment\n\t e\nx\n";
+    assert_eq!(kinds(text), (String::from(
+        "a newline indent b + f ( c , d ) newline indent e newline dedent dedent x newline"), None));
+    assert_eq!(kinds("a\n    b\n  c"), (String::from("a newline indent b newline dedent c newline"),
+                                      Some(String::from("dedent to column 2 at 10 matches no block"))));
+
+    // a block is a newline, an indent and statements up to the dedent;
+    // a statement that ends with a block has no newline of its own
+    g.nud("do", Rc::new(|se, this, pratt| {
+        expect(pratt, "newline");
+        expect(pratt, "indent");
+        while pratt.error().is_none() {
+            se.add_child(pratt.parse(0));
+            if at(pratt, "newline") {
+                pratt.advance();
+            }
+            if at(pratt, "dedent") {
+                pratt.advance();
+                break;
+            }
+        }
+        this
+    }));
+    let text = "do\n  a + 1\n  do\n    b\n  c * 2\nd\n";
+    let parser = g.parser(text);
+    assert_eq!(show(&parser.try_pparse().unwrap()), "(do (+ a 1) (do b) (* c 2))");
+    assert_eq!(show(&parser.current().unwrap()), "d");
+    // the layout tokens are empty, the text is on the others
+    assert_eq!(cst::parse(g.tokenizer("do\n  a + 1\n  do\n    b\n  c * 2\n")).unwrap().text(), "do\n  a + 1\n  do\n    b\n  c * 2\n");
+    // a dedent that matches no block is a syntax error
+    assert_eq!(g.parser("do\n    a\n  b\n").try_pparse().err(),
+               Some(ParseError::Syntax(String::from("dedent to column 2 at 11 matches no block"))));
+    // without factories of their own the layout tokens are literals
+    let tokenizer = language().tokenizer("a\n  b\n");
+    tokenizer.set_layout(true);
+    let kinds: Vec<String> = tokenizer.lex().into_iter().map(|(l, _)| l.kind).collect();
+    assert_eq!(kinds, vec!["literal", "newline", "indent", "literal", "newline", "dedent"]);
+    finish();
+}
+
+#[test]
+fn test_terminators() {
+    let g = language();
+    let terminators = |text: &str| {
+        let tokenizer = g.tokenizer(text);
+        tokenizer.set_lossless(true);
+        let end = g.rule("end").unwrap().clone();
+        let mut terminators = Terminators::new(tokenizer, "end", Box::new(move |s| end.token(s)));
+        for kind in &["num", "literal", "string", ")"] {
+            terminators.end_after(kind);
+        }
+        // a call's arguments go on over lines
+        terminators.continue_with(")", true);
+        terminators
+    };
+    let text = "a + 1\nb *\n  2\nf ( x\n)\nc . d";
+    let buf = SharedBuf(Rc::new(RefCell::new(Vec::new())));
+    let mut parser = Pratt::new(terminators(text));
+    parser.set_tracer(Rc::new(PrettyTracer::new(buf.clone())));
+    let results: Vec<_> = parser.expressions().map(|r| r.map(|ast| show(&ast))).collect();
+    assert_eq!(results, vec![
+        Ok(String::from("(+ a 1)")),
+        Ok(String::from("(* b 2)")),
+        Ok(String::from("(( f x)")),
+        Ok(String::from("c")),
+        Ok(String::from("d")),
+    ]);
+    // where the statements were split, the last one at the end of input
+    let trace = String::from_utf8(buf.0.borrow().clone()).unwrap();
+    assert_eq!(trace.lines().filter(|l| l.trim_start().starts_with("insert")).count(), 4);
+
+    // the made up terminator is empty, the text is kept
+    let events = events::parse(Pratt::new(terminators("a\n  + b\n")), Vec::new()).unwrap();
+    let tokens: Vec<_> = events.iter().filter_map(|e| match *e {
+        Event::Token(ref l) => Some((l.kind.as_str(), l.full_text())),
+        _ => None,
+    }).collect();
+    assert_eq!(tokens, vec![("literal", String::from("a\n")), ("+", String::from("  + ")),
+                            ("literal", String::from("b\n")), ("end", String::new())]);
+    finish();
+}
+
+#[test]
+fn test_restricted() {
+    use pratt::grammar::at;
+    let mut g = language();
+    // a call's '(' has to be on the line of the callee
+    g.restrict("(");
+    g.nud("return", Rc::new(|se, this, pratt| {
+        if !pratt.newline_before() && !at(pratt, "end") && pratt.current().is_some() {
+            se.add_child(pratt.parse(0));
+        }
+        this
+    }));
+    let text = "f (x)\ng\n(y)\nreturn\nz\nreturn 1 + 2\n";
+    let tokenizer = g.tokenizer(text);
+    let end = g.rule("end").unwrap().clone();
+    let mut terminators = Terminators::new(tokenizer, "end", Box::new(move |s| end.token(s)));
+    for kind in &["num", "literal", ")", "return"] {
+        terminators.end_after(kind);
+    }
+    let parser = Pratt::new(terminators);
+    let results: Vec<_> = parser.expressions().map(|r| r.map(|ast| show(&ast))).collect();
+    assert_eq!(results, vec![
+        Ok(String::from("(( f x)")),
+        Ok(String::from("g")),
+        Ok(String::from("y")),
+        Ok(String::from("return")),
+        Ok(String::from("z")),
+        Ok(String::from("(return (+ 1 2))")),
+    ]);
+
+    // without terminators the line break only stops the led
+    let ast = g.parser("g\n(y) .").try_pparse().unwrap();
+    assert_eq!(show(&ast), "g");
+    finish();
+}
+
+#[test]
+fn test_spacing() {
+    let grammar = language();
+    let lexemes: Vec<_> = grammar.tokenizer("f (x) -(y)").lex().into_iter()
+        .map(|(l, _)| (l.text, l.space_before, l.space_after)).collect();
+    assert_eq!(lexemes, vec![
+        (String::from("f"), false, true),
+        (String::from("("), true, false),
+        (String::from("x"), false, false),
+        (String::from(")"), false, true),
+        (String::from("-"), true, false),
+        (String::from("("), false, false),
+        (String::from("y"), false, false),
+        (String::from(")"), false, false),
+    ]);
+    // a comment counts as space
+    let tokenizer = grammar.tokenizer("a -# c\n b");
+    tokenizer.set_line_comment("#");
+    let lexemes: Vec<_> = tokenizer.lex().into_iter()
+        .map(|(l, _)| (l.text, l.space_before, l.space_after)).collect();
+    assert_eq!(lexemes, vec![
+        (String::from("a"), false, true),
+        (String::from("-"), true, true),
+        (String::from("b"), true, false),
+    ]);
+
+    let mut g = language();
+    g.call("[", ",", "]", 60);
+    g.group("[", "]");
+    // spaced on the left only, '(', '[' and '-' start an argument
+    g.spaced_prefix("(");
+    g.spaced_prefix("[");
+    g.spaced_prefix("-");
+    let parse = |text: &str| -> Vec<_> {
+        g.parser(text).expressions().map(|r| r.map(|ast| show(&ast))).collect()
+    };
+    let ok = |shown: &[&str]| -> Vec<_> { shown.iter().map(|s| Ok(String::from(*s))).collect() };
+    assert_eq!(parse("f(x) . f ( x ) ."), ok(&["(( f x)", "(( f x)"]));
+    assert_eq!(parse("a - (b) . (a)-(b) . a-b . x+1 ."), ok(&["(- a b)", "(- a b)", "(- a b)", "(+ x 1)"]));
+    assert_eq!(parse("f[x] ."), ok(&["([ f x)"]));
+    // without a juxtaposition the operand after 'f' is not taken:
+    // the expression ends before it, which is an error
+    let found = |token: &str| vec![Err(ParseError::Syntax(format!("expected end of expression, found {}", token)))];
+    assert_eq!(parse("f (x) ."), found("("));
+    assert_eq!(parse("f [x] ."), found("["));
+    assert_eq!(parse("a -b ."), found("-"));
+    finish();
+}
+
+#[test]
+fn test_juxtaposition() {
+    let mut g = language();
+    g.juxtaposition("apply", 70, Assoc::Left);
+    g.spaced_prefix("-");
+    let parse = |text: &str| show(&g.parser(text).try_pparse().unwrap());
+    assert_eq!(parse("f x y ."), "(apply (apply f x) y)");
+    assert_eq!(parse("f x + g 2 * y ."), "(+ (apply f x) (* (apply g 2) y))");
+    assert_eq!(parse("- f x ."), "(- (apply f x))");
+    assert_eq!(parse("2x ."), "(apply 2 x)");
+    // an explicit led comes first, spacing can take it away
+    assert_eq!(parse("f (x) (y) ."), "(( (( f x) y)");
+    assert_eq!(parse("f -(x) ."), "(apply f (- x))");
+    assert_eq!(parse("f - (x) ."), "(- f x)");
+    assert_eq!(parse("a -b . "), "(apply a (- b))");
+    assert_eq!(parse("a-b ."), "(- a b)");
+    // tokens without a nud still end the operand
+    assert_eq!(parse("if f x then a else b ."), "(if (apply f x) a b)");
+    // the tokenizer carries it: every parser over it juxtaposes, and so
+    // do the events
+    let src = "f x + g 2 * y .";
+    assert_eq!(show(&Pratt::new(g.tokenizer(src)).try_pparse().unwrap()), parse(src));
+    let boxed: BoxedPratt<DynamicSymbol> = Pratt::new(Box::new(g.tokenizer(src)));
+    assert_eq!(show(&boxed.try_pparse().unwrap()), parse(src));
+    let built = events::parse(g.parser(src), DynamicBuilder::new(&g)).unwrap().finish().unwrap();
+    assert_eq!(show(&built), parse(src));
+
+    let mut g = Grammar::arithmetic();
+    g.juxtaposition("apply", 70, Assoc::Left);
+    for &(src, printed) in [
+        ("f x y", "f x y"),
+        ("f ( x y )", "f (x y)"),
+        ("f ( a + b ) c", "f (a + b) c"),
+        ("( f x ) ^ 2", "f x ^ 2"),
+        ("- f x", "- f x"),
+        ("( - f ) x", "(- f) x"),
+        ("f ( - x )", "f (- x)"),
+    ].iter() {
+        let tree = g.parser(src).try_pparse().unwrap();
+        assert_eq!(printer::print(&g, &tree), printed);
+        let reparsed = g.parser(printed).try_pparse().unwrap();
+        assert_eq!(show(&reparsed), show(&tree), "printed as {}", printed);
+    }
+    finish();
+}